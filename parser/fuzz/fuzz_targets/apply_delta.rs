@@ -0,0 +1,12 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use rsgit::git::apply_delta;
+
+// The seeds in corpus/apply_delta are deltas against this base
+const BASE: &[u8] = b"fn main() {\n    println!(\"Hello, world!\");\n}\n";
+
+fuzz_target!(|delta: &[u8]| {
+    // Any delta has to come back as Ok or Err, a panic is a bug
+    let _ = apply_delta(BASE, delta);
+});
@@ -1,14 +1,36 @@
 use compress::zlib;
 use crypto::digest::Digest;
 use crypto::sha1::Sha1;
+use crypto::sha2::Sha256;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io;
 use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use serde::{Serialize, Deserialize};
 
-const SHA_SIZE: usize = 20;
+use crate::glob;
+use crate::index::PackIndex;
+use crate::refs::Refs;
+use crate::linediff;
+
+// The smallest a packed object can be: a one byte header and an empty zlib stream
+const MIN_PACKED_OBJECT_SIZE: usize = 9;
+
+pub type Sha = Vec<u8>;
 
 fn ashex(data: &[u8]) -> String {
     let mut res = String::with_capacity(data.len() * 2);
@@ -20,90 +42,534 @@ fn ashex(data: &[u8]) -> String {
 
 pub type GitTree = Vec<GitTreeEntry>;
 
-pub fn parse_tree(data: &[u8]) -> GitTree {
+/**
+ * Parses a tree object with shas of hash_size bytes. Fails if it has more than max_entries entries,
+ * so a corrupt or malicious tree can't make us allocate without bounds.
+ */
+pub fn parse_tree(data: &[u8], hash_size: usize, max_entries: usize) -> Result<GitTree, ParseError> {
     let mut entries = Vec::<GitTreeEntry>::new();
 
     /*
      * Tree format:
-     * 100644 git.ts\0<sha1>100644 nextfile.ts\0<sha1>...
+     * 100644 git.ts\0<sha>100644 nextfile.ts\0<sha>...
      */
     let mut entry_start_pos: usize = 0;
     let mut seek_pos: usize = 0;
     while seek_pos < data.len() {
         // Search for the next null byte, which will be in the middle of the next entry
         if data[seek_pos] == 0 {
-            entries.push(parse_entry(
-                &data[entry_start_pos..seek_pos],
-                &data[(seek_pos + 1)..=(seek_pos + SHA_SIZE)],
-            ));
-            entry_start_pos = seek_pos + SHA_SIZE + 1;
+            if entries.len() == max_entries {
+                return Err(ParseError::TooManyTreeEntries { limit: max_entries });
+            }
+            // A name without a mode or a cut off sha means we lost track of where entries start
+            let entry = data.get((seek_pos + 1)..=(seek_pos + hash_size))
+                .and_then(|sha| parse_entry(&data[entry_start_pos..seek_pos], sha))
+                .ok_or(ParseError::InvalidTreeEntry { offset: entry_start_pos })?;
+            entries.push(entry);
+            entry_start_pos = seek_pos + hash_size + 1;
             seek_pos = entry_start_pos;
             continue;
         }
         seek_pos += 1;
     }
+    if entry_start_pos < data.len() {
+        // Trailing bytes without a null byte
+        return Err(ParseError::InvalidTreeEntry { offset: entry_start_pos });
+    }
+
+    Ok(entries)
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+}
+
+/**
+ * A single entry that differs between two trees.
+ */
+pub struct FileChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    pub is_dir: bool,
+    pub old_path: Option<String>, // Only set for renames
+    pub old_sha: Option<Sha>, // Not set for additions
+    pub new_sha: Option<Sha>, // Not set for deletions
+    pub lines_added: Option<u32>, // Only with the myers feature, for files whose blobs were retained
+    pub lines_removed: Option<u32>,
+}
+
+/**
+ * A line of ParsePackResult::numstat.
+ */
+#[derive(PartialEq, Debug)]
+pub struct NumstatLine {
+    pub path: String,
+    pub added: Option<u32>, // None for binary files and files whose blobs weren't retained
+    pub removed: Option<u32>,
+}
+
+/**
+ * Compares the entries of two trees, without descending into subtrees.
+ *
+ * Entries are matched by name and kind, so a file that was replaced by a directory
+ * is a deletion plus an addition. A deleted and an added entry with identical contents
+ * are reported as a rename instead.
+ */
+pub fn diff_trees(a: &GitTree, b: &GitTree) -> Vec<FileChange> {
+    let mut changes = Vec::new();
+
+    for entry in a {
+        match b.iter().find(|&ent| ent.name == entry.name && ent.is_dir == entry.is_dir) {
+            Some(in_b) => {
+                if entry.sha != in_b.sha {
+                    changes.push(FileChange {
+                        path: entry.name.clone(),
+                        kind: ChangeKind::Modified,
+                        is_dir: entry.is_dir,
+                        old_path: None,
+                        old_sha: Some(entry.sha.clone()),
+                        new_sha: Some(in_b.sha.clone()),
+                        lines_added: None,
+                        lines_removed: None,
+                    });
+                }
+            }
+            None => changes.push(FileChange {
+                path: entry.name.clone(),
+                kind: ChangeKind::Deleted,
+                is_dir: entry.is_dir,
+                old_path: None,
+                old_sha: Some(entry.sha.clone()),
+                new_sha: None,
+                lines_added: None,
+                lines_removed: None,
+            }),
+        }
+    }
+
+    for entry in b {
+        if !a.iter().any(|ent| ent.name == entry.name && ent.is_dir == entry.is_dir) {
+            // An added entry with the same contents as a deleted one was moved
+            let deleted = changes.iter().position(|change| {
+                change.kind == ChangeKind::Deleted && change.is_dir == entry.is_dir && change.old_sha.as_ref() == Some(&entry.sha)
+            });
+
+            if let Some(index) = deleted {
+                let renamed = changes.remove(index);
+                changes.push(FileChange {
+                    path: entry.name.clone(),
+                    kind: ChangeKind::Renamed,
+                    is_dir: entry.is_dir,
+                    old_path: Some(renamed.path),
+                    old_sha: renamed.old_sha,
+                    new_sha: Some(entry.sha.clone()),
+                    lines_added: None,
+                    lines_removed: None,
+                });
+            } else {
+                changes.push(FileChange {
+                    path: entry.name.clone(),
+                    kind: ChangeKind::Added,
+                    is_dir: entry.is_dir,
+                    old_path: None,
+                    old_sha: None,
+                    new_sha: Some(entry.sha.clone()),
+                    lines_added: None,
+                    lines_removed: None,
+                });
+            }
+        }
+    }
 
-    entries
+    changes
 }
 
 pub struct GitTreeEntry {
-    is_dir: bool,
-    pub name: String,
-    sha: Vec<u8>,
+    pub mode: u32,
+    pub is_dir: bool,
+    pub name: String, // Invalid UTF-8 is replaced, so this is only for display if raw_name is set
+    pub raw_name: Option<Vec<u8>>, // The name as stored, only set if it isn't valid UTF-8
+    pub sha: Vec<u8>,
 }
 
-fn parse_entry(data: &[u8], sha: &[u8]) -> GitTreeEntry {
-    let entry_str = str::from_utf8(data).unwrap();
-    let mut parts = entry_str.split_whitespace();
-    let mode = parts.next().unwrap();
-    let name = parts.next().unwrap();
+/**
+ * What a tree entry is, going by its mode.
+ */
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum EntryKind {
+    Directory,
+    File,
+    Executable,
+    Symlink, // The blob holds the link target
+    Submodule, // Points to a commit in another repository, which is never in the pack
+}
 
-    GitTreeEntry {
-        is_dir: mode.as_bytes()[0] != b'1', // If mode starts with 1 it's a blob, so we believe it to be a tree otherwise
-        name: name.to_owned(),
-        sha: sha.to_vec()
+impl GitTreeEntry {
+    pub fn kind(&self) -> EntryKind {
+        if self.is_dir {
+            return EntryKind::Directory;
+        }
+        match self.mode {
+            0o100755 => EntryKind::Executable,
+            0o120000 => EntryKind::Symlink,
+            0o160000 => EntryKind::Submodule,
+            _ => EntryKind::File,
+        }
+    }
+
+    /**
+     * The type of the object the entry points to, as git cat-file prints it.
+     */
+    pub fn object_type(&self) -> &'static str {
+        match self.kind() {
+            EntryKind::Directory => "tree",
+            EntryKind::Submodule => "commit",
+            _ => "blob",
+        }
+    }
+}
+
+// The file type bits of a mode, and their value for a tree
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_TREE: u32 = 0o040000;
+
+fn parse_entry(data: &[u8], sha: &[u8]) -> Option<GitTreeEntry> {
+    // The mode ends at the first space, everything after it is the name, which can be any bytes but null
+    let space = data.iter().position(|&byte| byte == b' ')?;
+    let mode = str::from_utf8(&data[..space]).ok()?;
+    let raw_name = &data[space + 1..];
+    if mode.is_empty() || !mode.bytes().all(|byte| (b'0'..=b'7').contains(&byte)) || raw_name.is_empty() {
+        return None;
     }
+
+    // Git writes trees as 40000, without the leading zero of 040000, so go by the number rather than its digits
+    let mode = u32::from_str_radix(mode, 8).ok()?;
+
+    Some(GitTreeEntry {
+        mode,
+        is_dir: mode & MODE_TYPE_MASK == MODE_TREE,
+        name: String::from_utf8_lossy(raw_name).into_owned(),
+        raw_name: match str::from_utf8(raw_name) {
+            Ok(_) => None,
+            Err(_) => Some(raw_name.to_vec()),
+        },
+        sha: sha.to_vec()
+    })
 }
 
 pub struct GitCommit {
     tree_sha: Vec<u8>,
     parents: Vec<Vec<u8>>,
+    author: Option<Signature>,
+    committer: Option<Signature>,
+    gpgsig: Option<String>, // The signature block, without the leading spaces of its continuation lines
+    encoding: Option<String>, // The encoding of the message if it isn't UTF-8, e.g. "ISO-8859-1"
+    raw: Vec<u8>,
+    generation: u32, // Set once all commits of the pack are parsed, see set_generations
+}
+
+impl GitCommit {
+    pub fn tree_sha(&self) -> &[u8] {
+        &self.tree_sha
+    }
+
+    pub fn parents(&self) -> &[Sha] {
+        &self.parents
+    }
+
+    /**
+     * The topological level of the commit: 1 for a root commit, otherwise one more than the highest
+     * generation of its parents. Parents that aren't in the pack (e.g. in a shallow pack) are left out,
+     * so the history starts over at 1 where the pack cuts it off.
+     * A commit always has a higher generation than its ancestors, which lets walks stop early.
+     */
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /**
+     * The commit object as it is stored, headers and message.
+     */
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    pub fn author(&self) -> Option<&Signature> {
+        self.author.as_ref()
+    }
+
+    pub fn committer(&self) -> Option<&Signature> {
+        self.committer.as_ref()
+    }
+
+    /**
+     * The PGP or SSH signature of the commit, if it was signed. It is not verified.
+     */
+    pub fn signature(&self) -> Option<&str> {
+        self.gpgsig.as_deref()
+    }
+
+    /**
+     * Everything after the headers. With the encoding feature it's decoded from the encoding the commit
+     * declares, otherwise (or if the encoding is unknown) invalid UTF-8 is replaced.
+     */
+    pub fn message(&self) -> String {
+        match self.raw.windows(2).position(|window| window == b"\n\n") {
+            Some(start) => decode_text(&self.raw[start + 2..], self.encoding.as_deref()),
+            None => String::new(),
+        }
+    }
+
+    /**
+     * The trailers of the message, like ("Signed-off-by", "A <a@example.com>"), in the order they appear.
+     * They are the last paragraph of the message if every line of it is a "Key: value" line (or continues
+     * the one before with leading whitespace). The first paragraph is the summary, so it's never trailers.
+     */
+    pub fn trailers(&self) -> Vec<(String, String)> {
+        let message = self.message();
+        let paragraphs: Vec<&str> = message.split("\n\n").map(|paragraph| paragraph.trim_matches('\n')).filter(|paragraph| !paragraph.is_empty()).collect();
+        if paragraphs.len() < 2 {
+            return vec![];
+        }
+
+        let mut trailers: Vec<(String, String)> = Vec::new();
+        for line in paragraphs[paragraphs.len() - 1].lines() {
+            if line.starts_with(char::is_whitespace) {
+                match trailers.last_mut() {
+                    Some((_, value)) => {
+                        value.push(' ');
+                        value.push_str(line.trim());
+                    }
+                    None => return vec![],
+                }
+                continue
+            }
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') => (key, value),
+                _ => return vec![],
+            };
+            trailers.push((key.to_owned(), value.trim().to_owned()));
+        }
+        trailers
+    }
+
+    /**
+     * The people of the Co-authored-by trailers, as (name, email).
+     */
+    pub fn co_authors(&self) -> Vec<(String, String)> {
+        self.trailers().into_iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("Co-authored-by"))
+            .filter_map(|(_, value)| {
+                let email_start = value.rfind('<')?;
+                let email_end = value.rfind('>').filter(|&end| end > email_start)?;
+                Some((value[..email_start].trim().to_owned(), value[email_start + 1..email_end].to_owned()))
+            })
+            .collect()
+    }
+
+    /**
+     * The first line of the message.
+     */
+    pub fn summary(&self) -> String {
+        self.message().lines().next().unwrap_or("").to_owned()
+    }
+
+    /**
+     * The committer timestamp in unix seconds, or 0 if the commit has no committer line.
+     */
+    pub fn date(&self) -> i64 {
+        self.committer.as_ref().map_or(0, |committer| committer.time)
+    }
+
+    /**
+     * When the commit was originally written, which stays the same through rebases and cherry-picks.
+     * 0 if the commit has no author line.
+     */
+    pub fn author_date(&self) -> i64 {
+        self.author.as_ref().map_or(0, |author| author.time)
+    }
+}
+
+/**
+ * The identity and timestamp of an author or committer line.
+ */
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    pub time: i64,
+    pub tz_offset: i32, // In minutes east of UTC
+}
+
+fn parse_signature(value: &str) -> Option<Signature> {
+    /*
+     * Signature format:
+     * Some Name <some@email> 1600000000 +0200
+     */
+    let email_start = value.rfind('<')?;
+    let email_end = value.rfind('>')?;
+    if email_end < email_start {
+        return None;
+    }
+
+    let mut date = value[email_end + 1..].split_whitespace();
+    let time = date.next()?.parse::<i64>().ok()?;
+    let tz = date.next().unwrap_or("+0000");
+    let tz_value = tz.get(1..).and_then(|digits| digits.parse::<i32>().ok()).unwrap_or(0);
+    let tz_offset = (tz_value / 100 * 60 + tz_value % 100) * if tz.starts_with('-') { -1 } else { 1 };
+
+    Some(Signature {
+        name: value[..email_start].trim().to_owned(),
+        email: value[email_start + 1..email_end].to_owned(),
+        time,
+        tz_offset,
+    })
+}
+
+/**
+ * A commit as it is handed to JS, with all shas as hex strings instead of byte arrays.
+ */
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct CommitInfo {
+    pub sha: String,
+    pub parents: Vec<String>,
+    pub tree: String,
+    pub date: i64, // Same as committer_date
+    pub author_name: String, // Empty if the commit has no author line, same for the other signature fields
+    pub author_email: String,
+    pub author_date: i64,
+    pub author_tz_offset: i32, // In minutes east of UTC
+    pub committer_name: String,
+    pub committer_email: String,
+    pub committer_date: i64,
+    pub committer_tz_offset: i32,
+    pub summary: String,
+    pub message: String, // The whole message, see GitCommit::message
+    pub signed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    pub trailers: Vec<(String, String)>, // See GitCommit::trailers
+    pub generation: u32, // See GitCommit::generation
+}
+
+impl From<(&Sha, &GitCommit)> for CommitInfo {
+    fn from((sha, commit): (&Sha, &GitCommit)) -> CommitInfo {
+        CommitInfo {
+            sha: ashex(sha),
+            parents: commit.parents.iter().map(|parent| ashex(parent)).collect(),
+            tree: ashex(&commit.tree_sha),
+            date: commit.date(),
+            author_name: commit.author.as_ref().map_or(String::new(), |author| author.name.clone()),
+            author_email: commit.author.as_ref().map_or(String::new(), |author| author.email.clone()),
+            author_date: commit.author_date(),
+            author_tz_offset: commit.author.as_ref().map_or(0, |author| author.tz_offset),
+            committer_name: commit.committer.as_ref().map_or(String::new(), |committer| committer.name.clone()),
+            committer_email: commit.committer.as_ref().map_or(String::new(), |committer| committer.email.clone()),
+            committer_date: commit.date(),
+            committer_tz_offset: commit.committer.as_ref().map_or(0, |committer| committer.tz_offset),
+            summary: commit.summary(),
+            message: commit.message(),
+            signed: commit.gpgsig.is_some(),
+            signature: commit.gpgsig.clone(),
+            trailers: commit.trailers(),
+            generation: commit.generation,
+        }
+    }
 }
 
-pub fn parse_commit(data: &[u8]) -> GitCommit {
+pub fn parse_commit(data: &[u8]) -> Result<GitCommit, ParseError> {
     /*
      * Commit format:
      * tree <sha>\n
      * then 0 or more times:
      * parent <sha>\n
+     * author <signature>\n
+     * committer <signature>\n
+     * encoding <name>, if the message isn't UTF-8\n
+     * gpgsig <signature>, where every line after the first starts with a space\n
+     * mergetag <tag object>, when a signed tag was merged. It continues the same way, so the lines of the
+     *   embedded tag (object, type, its own signature...) are never taken for headers of the commit\n
      * ...some more lines we don't care about
      * \n\n
      * commit message
+     *
+     * Not every tool writes the headers in this order, so we only go by their names.
      */
 
-    let content = str::from_utf8(&data).unwrap();
-    let header = content.split("\n\n").nth(0).unwrap();
+    // Names and messages don't have to be UTF-8, but everything we need to parse is ASCII
+    let content = String::from_utf8_lossy(data);
+    let header = content.split("\n\n").next().unwrap_or("");
 
     let mut tree: Option<Vec<u8>> = None;
     let mut parents = Vec::<Vec<u8>>::new();
+    let mut author = None;
+    let mut committer = None;
+    let mut gpgsig = None;
+    let mut encoding = None;
 
-    for line in header.split("\n") {
+    // A value can go on over several lines, each following line starts with a space (e.g. gpgsig)
+    let mut headers = Vec::<(&str, String)>::new();
+    for line in header.split('\n') {
+        if let Some(continuation) = line.strip_prefix(' ') {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push('\n');
+                value.push_str(continuation);
+            }
+            continue
+        }
         let mut parts = line.splitn(2, ' ');
-        let name = parts.next().unwrap();
-        let value = parts.next().unwrap();
+        headers.push((parts.next().unwrap_or(""), parts.next().unwrap_or("").to_owned()));
+    }
+
+    for (name, value) in headers {
         match name {
-            "tree" => tree = Some(hex::decode(value).unwrap()),
-            "parent" => parents.push(hex::decode(value).unwrap()),
+            "tree" if tree.is_none() => tree = Some(decode_sha(&value)?),
+            "parent" => parents.push(decode_sha(&value)?),
+            "author" => author = parse_signature(&value),
+            "committer" => committer = parse_signature(&value),
+            "gpgsig" | "gpgsig-sha256" => gpgsig = Some(value),
+            "encoding" => encoding = Some(value),
             _ => {}
         }
     }
 
-    GitCommit {
-        tree_sha: tree.unwrap(), // We believe every commit to have a tree
+    Ok(GitCommit {
+        tree_sha: tree.ok_or(ParseError::MissingTree)?,
         parents,
+        author,
+        committer,
+        gpgsig,
+        encoding,
+        raw: data.to_vec(),
+        generation: 0,
+    })
+}
+
+#[cfg(feature = "encoding")]
+fn decode_text(data: &[u8], encoding: Option<&str>) -> String {
+    match encoding.and_then(|label| encoding_rs::Encoding::for_label(label.trim().as_bytes())) {
+        Some(encoding) => encoding.decode_without_bom_handling(data).0.into_owned(),
+        None => String::from_utf8_lossy(data).into_owned(),
     }
 }
 
+#[cfg(not(feature = "encoding"))]
+fn decode_text(data: &[u8], _encoding: Option<&str>) -> String {
+    String::from_utf8_lossy(data).into_owned()
+}
+
+fn looks_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|&byte| byte == 0)
+}
+
+fn decode_sha(value: &str) -> Result<Sha, ParseError> {
+    hex::decode(value).map_err(|_| ParseError::InvalidSha { value: value.to_owned() })
+}
+
 /**
  * A wrapper for a Read that counts how many bytes have been read.
  */
@@ -134,15 +600,25 @@ enum PackObjectType {
 }
 
 impl PackObjectType {
-    pub fn new(v: u8) -> PackObjectType {
+    pub fn new(v: u8) -> Option<PackObjectType> {
         match v {
-            1 => PackObjectType::ObjCommit,
-            2 => PackObjectType::ObjTree,
-            3 => PackObjectType::ObjBlob,
-            4 => PackObjectType::ObjTag,
-            6 => PackObjectType::ObjOfsDelta,
-            7 => PackObjectType::ObjRefDelta,
-            _ => panic!("Unknown pack object type {}", v),
+            1 => Some(PackObjectType::ObjCommit),
+            2 => Some(PackObjectType::ObjTree),
+            3 => Some(PackObjectType::ObjBlob),
+            4 => Some(PackObjectType::ObjTag),
+            6 => Some(PackObjectType::ObjOfsDelta),
+            7 => Some(PackObjectType::ObjRefDelta),
+            _ => None,
+        }
+    }
+
+    pub fn from_git_name(name: &str) -> Option<PackObjectType> {
+        match name {
+            "commit" => Some(PackObjectType::ObjCommit),
+            "tree" => Some(PackObjectType::ObjTree),
+            "blob" => Some(PackObjectType::ObjBlob),
+            "tag" => Some(PackObjectType::ObjTag),
+            _ => None,
         }
     }
 
@@ -160,334 +636,4396 @@ impl PackObjectType {
 struct PackObject {
     obj_type: PackObjectType,
     data: Vec<u8>,
+    depth: u32, // How many deltas were applied to get the data, 0 for objects stored whole
 }
 
+/**
+ * Serializes to just the metadata of the pack, the objects stay on the Rust side.
+ */
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ParsePackResult {
-    commits: HashMap<Vec<u8>, GitCommit>,
-    trees: HashMap<Vec<u8>, GitTree>
-}
-
-pub fn parse_pack(data: &[u8]) -> ParsePackResult {
-    // Read header
-    let magic = str::from_utf8(&data[0..4]).unwrap();
-    assert_eq!(magic, "PACK");
-    let _version = u32::from_be_bytes(data[4..8].try_into().unwrap());
-    let num_objects = u32::from_be_bytes(data[8..12].try_into().unwrap());
-
-    let mut count: u32 = 0;
-    let mut objects = HashMap::<Vec<u8>, PackObject>::new();
-
-    let mut p: usize = 12;
-
-    // Read all packed entries
-    while p < data.len() - SHA_SIZE {
-        count += 1;
-
-        // First read the n-byte type and len (unpacked) of the obj
-        let first_byte = data[p];
-
-        let mut obj_type = PackObjectType::new((first_byte << 1 >> 5) as u8);
-        let mut len = (first_byte << 4 >> 4) as u64;
-
-        let msb = 1 << 7;
-        let mut n = 0;
-        while data[p + n] & msb != 0 {
-            // While MSB for the current byte not set
-            n += 1;
-            let byte = (data[p + n] & !msb) as u64; // Without msb
-            len += byte << (4 + 7 * (n - 1)); // Shift bits into place
-        }
-        p += n + 1;
-
-        if obj_type == PackObjectType::ObjOfsDelta {
-            panic!("Unsupported.");
-        }
-
-        let mut delta_ref: Option<&[u8]> = None;
-        if obj_type == PackObjectType::ObjRefDelta {
-            /*
-                20-byte base object name if OBJ_REF_DELTA or a negative relative
-                offset from the delta object's position in the pack if this
-                is an OBJ_OFS_DELTA object
-            */
-            delta_ref = Some(&data[p..p + 20]);
-            p += 20;
-        }
-
-        let mut decompressed = Vec::new();
-        if len > 0 {
-            /*
-            * We actually don't know how long the zlib-compressed object is.
-            * So we just uncompress it and count how many bytes zlib is reading.
-            */
-            let mut counter = ReadCounter::<&[u8]> {
-                inner: &data[p..],
-                read: 0,
-            };
-
-            zlib::Decoder::new(&mut counter)
-                .read_to_end(&mut decompressed)
-                .unwrap();
+    pub version: u32,
+    pub hash_algorithm: HashAlgo,
+    pub stats: PackStats,
+    pub warnings: Vec<ParseWarning>,
+    #[serde(skip)]
+    locations: Vec<(Sha, usize, usize)>, // Offset and compressed length of each object, in pack order
+    #[serde(skip)]
+    commits: HashMap<Sha, GitCommit>,
+    #[serde(skip)]
+    trees: HashMap<Sha, GitTree>,
+    #[serde(skip)]
+    blobs: HashMap<Sha, Vec<u8>>, // Only filled if ParseOptions::retain_blobs is set
+}
 
-            // Our zlib implementation doesn't read the checksum at the end so we need to add 4 bytes
-            p += counter.read + 4;
-        } else {
-            // Empty object has this size
-            p += 8;
-        }
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct PackStats {
+    pub num_objects: u32,
+    pub num_deltas: u32, // How many objects were stored as deltas and resolved against their base
+    pub is_thin: bool, // Some deltas are against bases that aren't in the pack at all, so objects are missing
+    pub max_delta_depth: u32, // The longest chain of deltas applied to resolve an object
+    pub average_delta_depth: f64, // The average over the resolved deltas, 0 without any
+}
 
-        assert_eq!(len as usize, decompressed.len());
+/**
+ * The hash function used for object names in the pack.
+ */
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum HashAlgo {
+    Sha1,
+    Sha256,
+}
 
-        if let Some(delta_ref) = delta_ref {
-            if let Some(base_obj) = objects.get(delta_ref) {
-                let undeltified = apply_delta(&base_obj.data, &decompressed);
-                obj_type = base_obj.obj_type.clone(); // We take the type of the base obj
-                decompressed = undeltified; // And use the undeltified data
-            } else {
-                // The refed object comes later, we can't handle this yet
-                println!("refenced object not found: ignored")
-            }
+impl HashAlgo {
+    /**
+     * Length of an object name in bytes.
+     */
+    pub fn size(self) -> usize {
+        match self {
+            HashAlgo::Sha1 => 20,
+            HashAlgo::Sha256 => 32,
         }
+    }
 
-        if let Some(name) = obj_type.git_name() {
-            // It's a non-deltified object
-            let mut buf = Vec::new();
-            buf.extend(
-                format!("{} {}\0", name, decompressed.len())
-                    .as_bytes()
-                    .iter()
-                    .cloned(),
-            );
-            buf.extend(decompressed.iter().cloned());
+    /**
+     * Hashes data, e.g. an object with its header to get its name.
+     */
+    pub fn digest(self, data: &[u8]) -> Sha {
+        let mut hasher = self.hasher();
+        hasher.input(data);
 
-            let mut hasher = Sha1::new();
-            hasher.input(&buf);
+        let mut sha = vec![0; self.size()];
+        hasher.result(&mut sha);
+        sha
+    }
 
-            let mut sha = vec![0; SHA_SIZE];
-            hasher.result(&mut sha);
-            println!("inserting {} {}", name, ashex(&sha));
-            objects.insert(sha, PackObject {
-                obj_type,
-                data: decompressed
-            });
+    fn hasher(self) -> Box<dyn Digest> {
+        match self {
+            HashAlgo::Sha1 => Box::new(Sha1::new()),
+            HashAlgo::Sha256 => Box::new(Sha256::new()),
         }
     }
 
-    assert_eq!(count, num_objects);
+    /**
+     * A pack ends with a hash of everything before it, so whichever algorithm reproduces it is the one the pack uses.
+     */
+    fn detect(data: &[u8]) -> Result<HashAlgo, ParseError> {
+        for algo in [HashAlgo::Sha1, HashAlgo::Sha256] {
+            if data.len() < 12 + algo.size() {
+                continue
+            }
+            let (content, trailer) = data.split_at(data.len() - algo.size());
+            if algo.digest(content) == trailer {
+                return Ok(algo);
+            }
+        }
+        Err(ParseError::ChecksumMismatch)
+    }
+}
 
-    let mut commits = HashMap::<Vec<u8>, GitCommit>::new();
-    let mut trees = HashMap::<Vec<u8>, GitTree>::new();
+/**
+ * Something in the pack we couldn't make sense of but could continue past.
+ */
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum ParseWarning {
+    // A delta whose base isn't in the pack, the object is left out. For an OFS_DELTA on top of
+    // another delta this is the base that one is missing.
+    MissingDeltaBase { offset: usize, base: String },
+    // The same object is stored twice, which a well-formed pack never does. The later one is used.
+    DuplicateObject { offset: usize, sha: String },
+    // With ParseOptions::verify_integrity, an object that doesn't hash to the sha the index has for it
+    ShaMismatch { offset: usize, expected: String, got: String },
+}
 
-    for (sha, object) in &objects {
-        if object.obj_type == PackObjectType::ObjCommit {
-            let mut buf = Vec::new();
-            buf.extend(
-                format!("{} {}\0", object.obj_type.git_name().unwrap(), object.data.len())
-                    .as_bytes()
-                    .iter()
-                    .cloned(),
-            );
-            buf.extend(object.data.iter().cloned());
-            commits.insert((&sha).to_vec().clone(), parse_commit(&object.data[..]));
-        }
-        if object.obj_type == PackObjectType::ObjTree {
-            let mut buf = Vec::new();
-            buf.extend(
-                format!("{} {}\0", object.obj_type.git_name().unwrap(), object.data.len())
-                    .as_bytes()
-                    .iter()
-                    .cloned(),
-            );
-            buf.extend(object.data.iter().cloned());
-            trees.insert((&sha).to_vec().clone(), parse_tree(&object.data[..]));
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseWarning::MissingDeltaBase { offset, base } => write!(f, "Base {} of the delta at offset {} is not in the pack", base, offset),
+            ParseWarning::DuplicateObject { offset, sha } => write!(f, "Object {} at offset {} is in the pack twice", sha, offset),
+            ParseWarning::ShaMismatch { offset, expected, got } => write!(f, "Object at offset {} hashes to {} instead of {}", offset, got, expected),
         }
     }
+}
+
+#[derive(Clone)]
+pub struct ParseOptions {
+    pub retain_blobs: bool, // Keep blob contents around, e.g. for line-based metrics. Costs a lot of memory.
+    pub max_tree_entries: usize, // Trees with more entries than this are rejected as corrupt
+    pub abort: Option<Arc<AtomicBool>>, // Parsing stops with ParseError::Aborted once this is set
+    pub strict: bool, // Fail with ParseError::Warning instead of collecting warnings
+    pub index: Option<PackIndex>, // The .idx of the pack, if there is one
+    pub verify_integrity: bool, // Check that every object hashes to the sha the index has for it
+    pub loose_objects: Option<PathBuf>, // The objects directory of a repository (.git/objects), to look up delta bases missing from the packs in
+}
 
-    ParsePackResult {
-        commits: commits,
-        trees: trees
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            retain_blobs: false,
+            max_tree_entries: 1_000_000, // Far more than any real directory has
+            abort: None,
+            strict: false,
+            index: None,
+            verify_integrity: false,
+            loose_objects: None,
+        }
     }
 }
 
-fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
-    let mut result = Vec::new();
+/**
+ * Whether the caller asked us to stop, checked once per object or commit.
+ */
+fn aborted(abort: &Option<Arc<AtomicBool>>) -> bool {
+    abort.as_ref().is_some_and(|abort| abort.load(Ordering::Relaxed))
+}
 
-    let msb = 1 << 7 as u8;
+pub enum GitObject<'a> {
+    Commit(&'a GitCommit),
+    Tree(&'a GitTree),
+    Blob(&'a [u8]),
+}
 
-    let mut p = 0;
+impl ParsePackResult {
+    pub fn commit(&self, sha: &[u8]) -> Option<&GitCommit> {
+        self.commits.get(sha)
+    }
 
-    // Source length n-byte, that we ignore
-    while delta[p] & msb != 0 {
-        p += 1;
+    /**
+     * The entry at a path like "src/main.rs" below a tree, if it exists.
+     */
+    pub fn find_entry(&self, tree_sha: &[u8], path: &str) -> Option<&GitTreeEntry> {
+        let mut names = path.split('/').peekable();
+        let mut tree = self.trees.get(tree_sha)?;
+
+        while let Some(name) = names.next() {
+            let entry = tree.iter().find(|entry| entry.name == name)?;
+            if names.peek().is_none() {
+                return Some(entry);
+            }
+            tree = self.trees.get(&entry.sha)?;
+        }
+        None
     }
-    p += 1;
 
-    // Target length n-byte, that we ignore
-    while delta[p] & msb != 0 {
-        p += 1;
+    /**
+     * All files that differ between two trees, with their full paths.
+     * Unlike diff_trees this descends into directories, so only files are reported.
+     * Without a tree a, everything in b counts as added. Fails if a tree is missing from the pack, e.g. a shallow one.
+     */
+    pub fn diff_paths(&self, a: Option<&[u8]>, b: &[u8]) -> Result<Vec<FileChange>, ProcessError> {
+        let empty = GitTree::new();
+        let a = match a {
+            Some(sha) => self.tree_of(sha)?,
+            None => &empty,
+        };
+        let mut changes = Vec::new();
+        self.collect_diff(a, self.tree_of(b)?, "", "", &mut changes)?;
+        Ok(changes)
     }
-    p += 1;
 
-    // The rest of delta is series of instructions
-    while p < delta.len() {
-        let instr = delta[p];
-        p += 1;
+    fn tree_of(&self, sha: &[u8]) -> Result<&GitTree, ProcessError> {
+        self.trees.get(sha).ok_or_else(|| ProcessError::MissingTree { sha: ashex(sha) })
+    }
 
-        if instr == 0 {
-            // Reserved for future use
-            panic!("Instruction 0 not implemented");
-        } else if instr & msb != 0 {
-            // If msb is set, it's a copy from base instruction
-            let mut base_offset: u32 = 0;
-            let mut copy_size: u32 = 0;
+    fn collect_diff(&self, a: &GitTree, b: &GitTree, old_prefix: &str, new_prefix: &str, changes: &mut Vec<FileChange>) -> Result<(), ProcessError> {
+        let empty = GitTree::new();
 
-            if instr & 1 << 0 != 0 {
-                base_offset += delta[p] as u32;
-                p += 1;
-            }
-            if instr & 1 << 1 != 0 {
-                base_offset += (delta[p] as u32) << 8;
-                p += 1;
-            }
-            if instr & 1 << 2 != 0 {
-                base_offset += (delta[p] as u32) << 16;
-                p += 1;
-            }
-            if instr & 1 << 3 != 0 {
-                base_offset += (delta[p] as u32) << 24;
-                p += 1;
-            }
+        for change in diff_trees(a, b) {
+            let old_path = change.old_path.as_ref().unwrap_or(&change.path);
+            let old_dir = format!("{}{}/", old_prefix, old_path);
+            let new_dir = format!("{}{}/", new_prefix, change.path);
 
-            if instr & 1 << 4 != 0 {
-                copy_size += delta[p] as u32;
-                p += 1;
-            }
-            if instr & 1 << 5 != 0 {
-                copy_size += (delta[p] as u32) << 8;
-                p += 1;
-            }
-            if instr & 1 << 6 != 0 {
-                copy_size += (delta[p] as u32) << 16;
-                p += 1;
+            if !change.is_dir {
+                let lines = self.line_counts(&change);
+                changes.push(FileChange {
+                    path: format!("{}{}", new_prefix, change.path),
+                    old_path: change.old_path.as_ref().map(|old_path| format!("{}{}", old_prefix, old_path)),
+                    lines_added: lines.map(|(added, _)| added),
+                    lines_removed: lines.map(|(_, removed)| removed),
+                    ..change
+                });
+                continue
             }
 
-            let offset = base_offset as usize;
-            let size = copy_size as usize;
-
-            result.extend(&base[offset..offset + size]);
-        } else {
-            // Otherwise it's an instruction to add new data
-            let data_len = instr as usize;
-            result.extend(&delta[p..p + data_len]);
-            p += data_len;
+            match change.kind {
+                ChangeKind::Added => {
+                    let new = self.tree_of(change.new_sha.as_ref().unwrap())?;
+                    self.collect_diff(&empty, new, &old_dir, &new_dir, changes)?;
+                }
+                ChangeKind::Deleted => {
+                    let old = self.tree_of(change.old_sha.as_ref().unwrap())?;
+                    self.collect_diff(old, &empty, &old_dir, &new_dir, changes)?;
+                }
+                ChangeKind::Modified => {
+                    let old = self.tree_of(change.old_sha.as_ref().unwrap())?;
+                    let new = self.tree_of(change.new_sha.as_ref().unwrap())?;
+                    self.collect_diff(old, new, &old_dir, &new_dir, changes)?;
+                }
+                ChangeKind::Renamed => {
+                    // The contents are identical, so every file in it moved along
+                    let tree = self.tree_of(change.new_sha.as_ref().unwrap())?;
+                    self.collect_renamed(tree, &old_dir, &new_dir, changes)?;
+                }
+            }
         }
+        Ok(())
     }
 
-    result
-}
-
-pub struct ChangeCounter<'a> {
-    pack: &'a ParsePackResult,
-    processed_commits: HashSet<Vec<u8>>,
-    num_changes: HashMap<String, u32>
-}
+    /**
+     * The lines each file changed by a commit added and removed compared to its first parent, like `git show --numstat`.
+     * A root commit (or one whose parent isn't in the pack) is compared to an empty tree. The counts are None
+     * for binary files, which git shows as "-".
+     * Fails if the commit or one of the trees isn't in the pack.
+     */
+    #[cfg(feature = "myers")]
+    pub fn numstat(&self, commit: &[u8]) -> Result<Vec<NumstatLine>, ProcessError> {
+        let commit = self.commit(commit).ok_or_else(|| ProcessError::MissingCommit { sha: ashex(commit) })?;
+        let parent = commit.parents().first().and_then(|parent| self.commit(parent));
+        let changes = self.diff_paths(parent.map(|parent| parent.tree_sha()), commit.tree_sha())?;
 
-#[derive(Serialize, Deserialize)]
-pub struct TreeNode {
-	pub name: String,
-	pub r#type: String,
-	pub numChanges: u32,
-	pub children: Vec<Box<TreeNode>>
-}
+        Ok(changes.into_iter().map(|change| {
+            let binary = [&change.old_sha, &change.new_sha].iter()
+                .any(|sha| sha.as_ref().and_then(|sha| self.blobs.get(sha)).is_some_and(|data| looks_binary(data)));
+            NumstatLine {
+                path: change.path,
+                added: change.lines_added.filter(|_| !binary),
+                removed: change.lines_removed.filter(|_| !binary),
+            }
+        }).collect())
+    }
 
-impl ChangeCounter<'_> {
-    pub fn process(pack: &ParsePackResult, head_commit: &[u8]) -> TreeNode {
-        let mut comp = ChangeCounter {
-            pack,
-            processed_commits: HashSet::new(),
-            num_changes: HashMap::new()
+    /**
+     * Lines added and removed by a change of a file, if both versions were retained.
+     */
+    #[cfg(feature = "myers")]
+    fn line_counts(&self, change: &FileChange) -> Option<(u32, u32)> {
+        let empty = Vec::new();
+        let old = match &change.old_sha {
+            Some(sha) => self.blobs.get(sha)?,
+            None => &empty,
+        };
+        let new = match &change.new_sha {
+            Some(sha) => self.blobs.get(sha)?,
+            None => &empty,
         };
-        let head = pack.commits.get(head_commit).unwrap();
-        comp.walk_commit(head_commit);
-        comp.build_tree_node(String::from("/"), String::from(""), &head.tree_sha)
+        Some(linediff::line_diff(old, new, false))
     }
 
-    fn count_change(&mut self, path: String) {
-        let previous = self.num_changes.get(&path).unwrap_or(&0);
-        self.num_changes.insert(path, previous + 1);
+    #[cfg(not(feature = "myers"))]
+    fn line_counts(&self, _change: &FileChange) -> Option<(u32, u32)> {
+        None
     }
 
-    fn record_changes(&mut self, from_tree: &[u8], to_tree: &[u8], prefix: Vec<String>) {
-        if from_tree == to_tree {
-            // Trees are identical
-            return
-        }
-
-        let a = self.pack.trees.get(from_tree).unwrap();
-        let b = self.pack.trees.get(to_tree).unwrap();
-    
-        for entry in a {
+    fn collect_renamed(&self, tree: &GitTree, old_prefix: &str, new_prefix: &str, changes: &mut Vec<FileChange>) -> Result<(), ProcessError> {
+        for entry in tree {
             if entry.is_dir {
-                if let Some(in_b) = b.iter().find(|&ent| ent.name == entry.name && ent.is_dir) {
-                    if entry.sha != in_b.sha {
-                        // There were changes in the dir
-                        let mut new_prefix = prefix.clone();
-                        new_prefix.push(format!("{}{}/", prefix.last().unwrap(), entry.name));
-                        self.record_changes(&entry.sha, &in_b.sha, new_prefix)
-                    }
-                } // Otherwise the dir was deleted (or moved)
+                let tree = self.tree_of(&entry.sha)?;
+                let old_dir = format!("{}{}/", old_prefix, entry.name);
+                let new_dir = format!("{}{}/", new_prefix, entry.name);
+                self.collect_renamed(tree, &old_dir, &new_dir, changes)?;
             } else {
-                if let Some(in_b) = b.iter().find(|&ent| ent.name == entry.name && !ent.is_dir) {
-                    if entry.sha != in_b.sha {
-                        for dir in &prefix {
-                            self.count_change(dir.to_string());
-                        }
-                        self.count_change(format!("{}{}", prefix.last().unwrap(), entry.name));
-                    }
-                } // Otherwise the file was deleted (or moved)
+                let mut change = FileChange {
+                    path: format!("{}{}", new_prefix, entry.name),
+                    kind: ChangeKind::Renamed,
+                    is_dir: false,
+                    old_path: Some(format!("{}{}", old_prefix, entry.name)),
+                    old_sha: Some(entry.sha.clone()),
+                    new_sha: Some(entry.sha.clone()),
+                    lines_added: None,
+                    lines_removed: None,
+                };
+                let lines = self.line_counts(&change);
+                change.lines_added = lines.map(|(added, _)| added);
+                change.lines_removed = lines.map(|(_, removed)| removed);
+                changes.push(change);
             }
         }
+        Ok(())
     }
-    
-    fn walk_commit(&mut self, commit_sha: &[u8]) {
-        if self.processed_commits.contains(commit_sha) {
-            return
-        }
-        self.processed_commits.insert(commit_sha.to_vec());
-    
-        let commit = self.pack.commits.get(commit_sha).unwrap();
 
-        for parent_sha in &commit.parents {
-            let parent = self.pack.commits.get(parent_sha).unwrap();
-            self.record_changes(&parent.tree_sha, &commit.tree_sha, vec![String::from("/")]);
-            self.walk_commit(parent_sha);
+    /**
+     * Looks up an object by sha. Blobs can only be found if they were retained when parsing.
+     */
+    pub fn get_object(&self, sha: &[u8]) -> Option<GitObject<'_>> {
+        if let Some(commit) = self.commits.get(sha) {
+            return Some(GitObject::Commit(commit));
+        }
+        if let Some(tree) = self.trees.get(sha) {
+            return Some(GitObject::Tree(tree));
         }
+        self.blobs.get(sha).map(|blob| GitObject::Blob(blob))
     }
 
-    fn build_tree_node(&self, path: String, name: String, tree_sha: &[u8]) -> TreeNode {
-        let tree = self.pack.trees.get(tree_sha).unwrap();
+    /**
+     * Where each object is in the pack, in the order they are stored: its sha, the offset of its
+     * header and the length of its zlib stream. Deltas are listed under the sha of the resolved object.
+     */
+    pub fn object_locations(&self) -> Vec<(Sha, usize, usize)> {
+        self.locations.clone()
+    }
+
+    /**
+     * The commits in the pack with their shas, in no particular order.
+     */
+    pub fn commits(&self) -> impl Iterator<Item = (&Sha, &GitCommit)> {
+        self.commits.iter()
+    }
+
+    /**
+     * All commits in the pack, ordered by sha so the output is stable.
+     */
+    pub fn commit_infos(&self) -> Vec<CommitInfo> {
+        let mut infos: Vec<CommitInfo> = self.commits.iter().map(CommitInfo::from).collect();
+        infos.sort_by(|a, b| a.sha.cmp(&b.sha));
+        infos
+    }
+
+    /**
+     * Roughly how much memory the parsed objects take: the commit, tree and blob maps at their capacity
+     * (with one control byte per slot, like std's hash map has), the data each commit, tree entry and
+     * blob holds on the heap, and the object locations. Allocator overhead and the stats are left out.
+     */
+    pub fn approx_memory_bytes(&self) -> usize {
+        fn map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+            map.capacity() * (mem::size_of::<(K, V)>() + 1)
+        }
+        fn signature_bytes(signature: &Option<Signature>) -> usize {
+            signature.as_ref().map_or(0, |signature| signature.name.capacity() + signature.email.capacity())
+        }
+
+        let commits: usize = self.commits.iter()
+            .map(|(sha, commit)| {
+                sha.capacity()
+                    + commit.tree_sha.capacity()
+                    + commit.parents.capacity() * mem::size_of::<Sha>()
+                    + commit.parents.iter().map(|parent| parent.capacity()).sum::<usize>()
+                    + signature_bytes(&commit.author)
+                    + signature_bytes(&commit.committer)
+                    + commit.gpgsig.as_ref().map_or(0, |gpgsig| gpgsig.capacity())
+                    + commit.encoding.as_ref().map_or(0, |encoding| encoding.capacity())
+                    + commit.raw.capacity()
+            })
+            .sum();
+        let trees: usize = self.trees.iter()
+            .map(|(sha, tree)| {
+                sha.capacity()
+                    + tree.capacity() * mem::size_of::<GitTreeEntry>()
+                    + tree.iter().map(|entry| {
+                        entry.name.capacity() + entry.sha.capacity() + entry.raw_name.as_ref().map_or(0, |raw_name| raw_name.capacity())
+                    }).sum::<usize>()
+            })
+            .sum();
+        let blobs: usize = self.blobs.iter().map(|(sha, blob)| sha.capacity() + blob.capacity()).sum();
+        let locations = self.locations.capacity() * mem::size_of::<(Sha, usize, usize)>()
+            + self.locations.iter().map(|(sha, _, _)| sha.capacity()).sum::<usize>();
+
+        map_bytes(&self.commits) + map_bytes(&self.trees) + map_bytes(&self.blobs) + commits + trees + blobs + locations
+    }
+
+    /**
+     * A reader for the blobs of the pack this was parsed from, for when they weren't retained.
+     * Only works with the result of a single pack, with several of them the offsets are mixed up.
+     * The capacity most recently read blobs are kept in memory.
+     */
+    pub fn blob_reader<'p>(&self, pack: &'p [u8], capacity: usize) -> Result<BlobReader<'p>, ParseError> {
+        Ok(BlobReader {
+            pack: strip_pack_framing(pack)?,
+            hash_size: self.hash_algorithm.size(),
+            offsets: self.locations.iter().map(|(sha, offset, _)| (sha.clone(), *offset)).collect(),
+            capacity: capacity.max(1),
+            cache: VecDeque::new(),
+        })
+    }
+}
+
+/**
+ * Decompresses blobs from the pack bytes one at a time when they are asked for, instead of keeping
+ * all of them in memory like ParseOptions::retain_blobs does.
+ */
+pub struct BlobReader<'p> {
+    pack: &'p [u8],
+    hash_size: usize,
+    offsets: HashMap<Sha, usize>, // Where each object starts in the pack
+    capacity: usize,
+    cache: VecDeque<(Sha, Vec<u8>)>, // The most recently read blobs, most recent first
+}
+
+impl<'p> BlobReader<'p> {
+    /**
+     * The contents of a blob, or None if the pack doesn't have it (or its delta base) or it isn't a blob.
+     */
+    pub fn get_blob(&mut self, sha: &[u8]) -> Result<Option<&[u8]>, ParseError> {
+        if let Some(i) = self.cache.iter().position(|(cached, _)| cached == sha) {
+            let blob = self.cache.remove(i).unwrap();
+            self.cache.push_front(blob);
+            return Ok(Some(&self.cache[0].1));
+        }
+
+        let data = match self.offsets.get(sha) {
+            Some(&offset) => match self.read_object(offset)? {
+                Some((PackObjectType::ObjBlob, data)) => data,
+                _ => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        self.cache.push_front((sha.to_vec(), data));
+        self.cache.truncate(self.capacity);
+        Ok(Some(&self.cache[0].1))
+    }
+
+    /**
+     * The type and contents of the object starting at offset, with its chain of deltas applied.
+     */
+    fn read_object(&self, mut offset: usize) -> Result<Option<(PackObjectType, Vec<u8>)>, ParseError> {
+        let mut deltas = Vec::new();
+        let (obj_type, mut data) = loop {
+            // A chain can't be longer than there are objects, unless the pack is corrupt and it loops
+            if deltas.len() > self.offsets.len() {
+                return Err(ParseError::InvalidDeltaOffset { offset });
+            }
+            let entry = read_entry(self.pack.get(offset..).ok_or(ParseError::InvalidDeltaOffset { offset })?, offset, self.hash_size)?;
+            offset = match entry.base {
+                Some(DeltaBase::Offset(base_offset)) => base_offset,
+                Some(DeltaBase::Sha(base)) => match self.offsets.get(base) {
+                    Some(&base_offset) => base_offset,
+                    None => return Ok(None),
+                },
+                None => break (entry.obj_type, entry.data),
+            };
+            deltas.push(entry.data);
+        };
+
+        for delta in deltas.iter().rev() {
+            data = apply_delta(&data, delta)?;
+        }
+        Ok(Some((obj_type, data)))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /*
+     * The deflate stream of the object starting at offset is corrupt.
+     * We don't know how long the compressed data is supposed to be, so we can't skip to the next object.
+     */
+    ZlibError { offset: usize },
+    // A commit without a tree line
+    MissingTree,
+    // A sha in a commit header that isn't valid hex
+    InvalidSha { value: String },
+    // A tree with more entries than ParseOptions::max_tree_entries
+    TooManyTreeEntries { limit: usize },
+    // The abort flag of the ParseOptions was set
+    Aborted,
+    // The pack doesn't end with a SHA-1 or SHA-256 checksum of its contents, so it's truncated or corrupt
+    ChecksumMismatch,
+    // Something that would only be a warning, with ParseOptions::strict
+    Warning(ParseWarning),
+    // A pack index that isn't a version 2 .idx or is cut short
+    InvalidIndex,
+    // A tree entry without a mode or name, or with a cut off sha, at offset in the tree's data
+    InvalidTreeEntry { offset: usize },
+    // An OFS_DELTA at offset whose base offset isn't where an object starts
+    InvalidDeltaOffset { offset: usize },
+    // Data that doesn't have the PACK magic near its start
+    MissingPackHeader,
+    // A delta that is cut off, has an unknown instruction or copies from outside its base, at offset in the delta's data
+    InvalidDelta { offset: usize },
+    // The pack ran out of data after `found` of the `declared` objects
+    MissingObjects { declared: u32, found: u32 },
+    // The pack holds more objects (found) than its header declares
+    TooManyObjects { declared: u32, found: u32 },
+    // An entry with a type that isn't one of the object or delta types (0 and 5 are unused)
+    UnknownObjectType { offset: usize, obj_type: u8 },
+    // An entry whose header runs past the end of the pack
+    TruncatedEntry { offset: usize },
+    // An entry that decompresses to a different size than its header declares
+    SizeMismatch { offset: usize },
+    // A commit-graph file that is cut short, doesn't match its checksum or has a parent outside of it
+    InvalidCommitGraph,
+    // The pack-th of the packs given to parse_packs doesn't use the hash algorithm of the first one
+    HashAlgorithmMismatch { pack: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::ZlibError { offset } => write!(f, "Could not decompress the object at offset {}", offset),
+            ParseError::MissingTree => write!(f, "Commit has no tree"),
+            ParseError::InvalidSha { value } => write!(f, "Invalid sha in commit: {}", value),
+            ParseError::TooManyTreeEntries { limit } => write!(f, "Tree has more than {} entries", limit),
+            ParseError::Aborted => write!(f, "Parsing was aborted"),
+            ParseError::ChecksumMismatch => write!(f, "Pack checksum does not match"),
+            ParseError::Warning(warning) => write!(f, "{}", warning),
+            ParseError::InvalidIndex => write!(f, "Invalid pack index"),
+            ParseError::InvalidTreeEntry { offset } => write!(f, "Invalid tree entry at offset {}", offset),
+            ParseError::InvalidDeltaOffset { offset } => write!(f, "Base of the delta at offset {} is not an object", offset),
+            ParseError::MissingPackHeader => write!(f, "Not a pack"),
+            ParseError::InvalidDelta { offset } => write!(f, "Invalid delta instruction at offset {}", offset),
+            ParseError::MissingObjects { declared, found } => write!(f, "Pack ends after {} of the {} objects it declares", found, declared),
+            ParseError::TooManyObjects { declared, found } => write!(f, "Pack declares {} objects but holds {}", declared, found),
+            ParseError::UnknownObjectType { offset, obj_type } => write!(f, "Unknown object type {} at offset {}", obj_type, offset),
+            ParseError::TruncatedEntry { offset } => write!(f, "The object at offset {} is cut off", offset),
+            ParseError::SizeMismatch { offset } => write!(f, "The object at offset {} is not the size its header declares", offset),
+            ParseError::InvalidCommitGraph => write!(f, "Invalid commit-graph file"),
+            ParseError::HashAlgorithmMismatch { pack } => write!(f, "Pack {} does not use the hash algorithm of the first pack", pack),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/**
+ * Fails if a pack whose header declares declared objects held found of them.
+ */
+fn check_object_count(declared: u32, found: u32) -> Result<(), ParseError> {
+    if found < declared {
+        return Err(ParseError::MissingObjects { declared, found });
+    }
+    if found > declared {
+        return Err(ParseError::TooManyObjects { declared, found });
+    }
+    Ok(())
+}
+
+fn warn(warnings: &mut Vec<ParseWarning>, warning: ParseWarning, options: &ParseOptions) -> Result<(), ParseError> {
+    if options.strict {
+        return Err(ParseError::Warning(warning));
+    }
+    warnings.push(warning);
+    Ok(())
+}
+
+/**
+ * The pack without whatever the transport put in front of it, like the "0008NAK\n" pkt-line of a fetch
+ * response, so it starts with the PACK magic. The magic has to start within the first 16 bytes.
+ */
+pub fn strip_pack_framing(data: &[u8]) -> Result<&[u8], ParseError> {
+    match data.windows(4).take(16).position(|window| window == b"PACK") {
+        Some(start) => Ok(&data[start..]),
+        None => Err(ParseError::MissingPackHeader),
+    }
+}
+
+pub fn parse_pack(data: &[u8]) -> Result<ParsePackResult, ParseError> {
+    parse_pack_with_options(data, &ParseOptions::default())
+}
+
+pub fn parse_pack_with_options(data: &[u8], options: &ParseOptions) -> Result<ParsePackResult, ParseError> {
+    parse_packs(&[data], options)
+}
+
+/**
+ * Reads several packs into one result, like the packs in .git/objects/pack of a repository.
+ * Deltas can have their base in any of the packs. The version is taken from the first pack, and
+ * object_locations has the offsets of every pack mixed together. All packs have to use the same hash algorithm.
+ * The index of the ParseOptions is only used to verify a single pack. Framing in front of a pack is skipped,
+ * see strip_pack_framing.
+ */
+pub fn parse_packs(packs: &[&[u8]], options: &ParseOptions) -> Result<ParsePackResult, ParseError> {
+    let packs = packs.iter().map(|data| strip_pack_framing(data)).collect::<Result<Vec<&[u8]>, ParseError>>()?;
+    let mut hash_algorithm = HashAlgo::Sha1;
+    for (i, data) in packs.iter().enumerate() {
+        let algo = HashAlgo::detect(data)?;
+        if i == 0 {
+            hash_algorithm = algo;
+        } else if algo != hash_algorithm {
+            return Err(ParseError::HashAlgorithmMismatch { pack: i });
+        }
+    }
+    let indexed = match &options.index {
+        Some(index) if options.verify_integrity && packs.len() == 1 => index.by_offset(),
+        _ => HashMap::new(),
+    };
+
+    let mut store = ObjectStore::new(Cow::Borrowed(options), hash_algorithm);
+    let mut version = 2;
+    for (i, data) in packs.iter().enumerate() {
+        let pack_version = store.read_pack(i, data, &indexed)?;
+        if i == 0 {
+            version = pack_version;
+        }
+    }
+    store.finish(version)
+}
+
+/**
+ * Parses a pack as it arrives in chunks, e.g. from a fetch, so it never has to be in memory as a whole.
+ * Every object is read as soon as all of its data is there, only the data of an object that hasn't
+ * fully arrived yet is kept. The hash algorithm can't be told from the checksum at the end of the pack
+ * before everything is read, so it has to be passed in. Framing in front of the pack is skipped, see
+ * strip_pack_framing, and the index of the ParseOptions isn't used.
+ */
+pub struct PackStreamParser {
+    store: ObjectStore<'static>,
+    buffer: Vec<u8>, // What arrived but isn't read yet
+    offset: usize, // Where buffer starts in the pack
+    header: Option<(u32, u32)>, // The version and the number of objects, once they arrived
+    count: u32, // Objects read so far
+    retry_len: usize, // How long buffer has to get before trying again to read an object that was cut off
+    checksum: Box<dyn Digest>, // Of everything read so far, to compare with the one at the end of the pack
+}
+
+impl PackStreamParser {
+    pub fn new(options: ParseOptions, hash_algorithm: HashAlgo) -> PackStreamParser {
+        PackStreamParser {
+            store: ObjectStore::new(Cow::Owned(options), hash_algorithm),
+            buffer: Vec::new(),
+            offset: 0,
+            header: None,
+            count: 0,
+            retry_len: 0,
+            checksum: hash_algorithm.hasher(),
+        }
+    }
+
+    /**
+     * Adds the next chunk of the pack and reads the objects that are complete now.
+     */
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), ParseError> {
+        self.buffer.extend_from_slice(chunk);
+        self.read_available(false)
+    }
+
+    /**
+     * Reads what is left once the whole pack arrived, checks its checksum and parses the commits and trees.
+     */
+    pub fn finish(mut self) -> Result<ParsePackResult, ParseError> {
+        self.read_available(true)?;
+        let (version, num_objects) = self.header.ok_or(ParseError::MissingPackHeader)?;
+
+        // Objects after the declared ones, to tell a pack with too many of them from a corrupt one like parse_pack does
+        let hash_size = self.store.hash_algorithm.size();
+        let mut found = self.count;
+        let mut p = 0;
+        while self.buffer.len() - p > hash_size {
+            match read_entry(&self.buffer[p..], self.offset + p, hash_size) {
+                Ok(entry) => p += entry.len,
+                Err(_) => break,
+            }
+            found += 1;
+        }
+        check_object_count(num_objects, found)?;
+
+        let mut checksum = vec![0; self.store.hash_algorithm.size()];
+        self.checksum.result(&mut checksum);
+        if self.buffer != checksum {
+            return Err(ParseError::ChecksumMismatch);
+        }
+        self.store.finish(version)
+    }
+
+    /**
+     * Reads the header once it's there, skipping what comes before the magic.
+     */
+    fn read_header(&mut self, finished: bool) -> Result<(), ParseError> {
+        let start = match self.buffer.windows(4).take(16).position(|window| window == b"PACK") {
+            Some(start) => start,
+            // The magic could still be cut off at the end of what arrived so far
+            None if !finished && self.buffer.len() < 16 + 3 => return Ok(()),
+            None => return Err(ParseError::MissingPackHeader),
+        };
+        let header = match self.buffer.get(start..start + 12) {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+
+        let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let num_objects = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        self.checksum.input(header);
+        self.store.num_objects += num_objects;
+        self.header = Some((version, num_objects));
+        self.buffer.drain(..start + 12);
+        self.offset = 12;
+        Ok(())
+    }
+
+    fn read_available(&mut self, finished: bool) -> Result<(), ParseError> {
+        // Checked here too, as a chunk may not be enough to get to the next object
+        if aborted(&self.store.options.abort) {
+            return Err(ParseError::Aborted);
+        }
+        if self.header.is_none() {
+            self.read_header(finished)?;
+        }
+        let num_objects = match self.header {
+            Some((_, num_objects)) => num_objects,
+            None => return Ok(()),
+        };
+        // Decompressing an object that is cut off fails only at its end, so wait for a lot more data
+        // before trying again. Otherwise a large object would be decompressed for every chunk.
+        if !finished && self.buffer.len() < self.retry_len {
+            return Ok(());
+        }
+
+        let hash_size = self.store.hash_algorithm.size();
+        let mut p = 0;
+        let mut result = Ok(());
+        while self.count < num_objects {
+            if aborted(&self.store.options.abort) {
+                result = Err(ParseError::Aborted);
+                break;
+            }
+            // The objects end where the checksum starts. Until the pack is complete, what is left could be that checksum
+            // of a pack with fewer objects than it declares, which isn't an object to fail on.
+            if self.buffer.len() - p <= hash_size {
+                break;
+            }
+            let offset = self.offset + p;
+            match read_entry(&self.buffer[p..], offset, hash_size) {
+                Ok(entry) => {
+                    p += entry.len;
+                    self.count += 1;
+                    if let Err(error) = self.store.add_entry(0, offset, entry, None) {
+                        result = Err(error);
+                        break;
+                    }
+                }
+                // The rest of the object hasn't arrived yet, or it is corrupt, which will show once it has
+                Err(ParseError::TruncatedEntry { .. } | ParseError::ZlibError { .. } | ParseError::SizeMismatch { .. }) if !finished => break,
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            }
+        }
+
+        self.checksum.input(&self.buffer[..p]);
+        self.buffer.drain(..p);
+        self.offset += p;
+        self.retry_len = self.buffer.len() * 2;
+        result
+    }
+}
+
+/**
+ * A delta waiting for its base object to be read.
+ */
+struct PendingDelta {
+    pack: usize,
+    offset: usize,
+    compressed_len: usize,
+    delta: Vec<u8>,
+    expected: Option<Sha>, // From the index, with ParseOptions::verify_integrity
+}
+
+/**
+ * The objects read from the packs so far, before commits and trees are parsed.
+ */
+struct ObjectStore<'o> {
+    options: Cow<'o, ParseOptions>, // Owned by a PackStreamParser, which can't borrow them
+    hash_algorithm: HashAlgo,
+    objects: HashMap<Sha, PackObject>,
+    pending: HashMap<Sha, Vec<PendingDelta>>, // By the base they are waiting for
+    warnings: Vec<ParseWarning>,
+    locations: Vec<(Sha, usize, usize)>,
+    num_objects: u32,
+    num_deltas: u32,
+    max_delta_depth: u32,
+    total_delta_depth: u64,
+    offset_to_sha: HashMap<(usize, usize), Sha>, // The objects read so far by pack and offset, for OFS_DELTA bases
+    pending_offsets: HashMap<(usize, usize), Vec<PendingDelta>>, // OFS_DELTAs whose base is a delta that is pending too
+    waiting: HashMap<(usize, usize), Sha>, // The base each pending delta is in the end waiting for, by pack and offset
+}
+
+impl<'o> ObjectStore<'o> {
+    fn new(options: Cow<'o, ParseOptions>, hash_algorithm: HashAlgo) -> ObjectStore<'o> {
+        ObjectStore {
+            options,
+            hash_algorithm,
+            objects: HashMap::new(),
+            pending: HashMap::new(),
+            warnings: Vec::new(),
+            locations: Vec::new(),
+            num_objects: 0,
+            num_deltas: 0,
+            max_delta_depth: 0,
+            total_delta_depth: 0,
+            offset_to_sha: HashMap::new(),
+            pending_offsets: HashMap::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    /**
+     * Reads all objects of the pack-th pack and returns its version. The pack has to end with a checksum
+     * of the hash algorithm of the store, which parse_packs detected.
+     */
+    fn read_pack(&mut self, pack: usize, data: &[u8], indexed: &HashMap<usize, &Sha>) -> Result<u32, ParseError> {
+        // Read header, parse_packs made sure it starts with the magic and detect that it is long enough for it
+        let hash_size = self.hash_algorithm.size();
+        let version = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        let num_objects = u32::from_be_bytes(data[8..12].try_into().unwrap());
+        self.num_objects += num_objects;
+
+        // The header can claim any number of objects, but no more than that can fit in the data
+        let capacity = (num_objects as usize).min(data.len() / MIN_PACKED_OBJECT_SIZE);
+        self.objects.reserve(capacity);
+        self.locations.reserve(capacity);
+
+        let mut count: u32 = 0;
+        let mut p: usize = 12;
+
+        // Read all packed entries
+        while p < data.len() - hash_size {
+            if aborted(&self.options.abort) {
+                return Err(ParseError::Aborted);
+            }
+            count += 1;
+            let offset = p;
+
+            let entry = read_entry(&data[p..], offset, hash_size)?;
+            p += entry.len;
+            let expected = indexed.get(&offset).map(|&sha| sha.clone());
+            self.add_entry(pack, offset, entry, expected)?;
+        }
+
+        check_object_count(num_objects, count)?;
+        Ok(version)
+    }
+
+    /**
+     * Adds the object of an entry read at offset of the pack-th pack, or keeps it for later if it's a delta whose base isn't read yet.
+     */
+    fn add_entry(&mut self, pack: usize, offset: usize, entry: PackEntry, expected: Option<Sha>) -> Result<(), ParseError> {
+        let obj_type = entry.obj_type;
+        let compressed_len = entry.compressed_len;
+        let decompressed = entry.data;
+        let (delta_ref, base_offset) = match entry.base {
+            Some(DeltaBase::Sha(sha)) => (Some(sha), None),
+            Some(DeltaBase::Offset(base_offset)) => (None, Some(base_offset)),
+            None => (None, None),
+        };
+
+        let delta = |delta: Vec<u8>| PendingDelta {
+            pack,
+            offset,
+            compressed_len,
+            delta,
+            expected: expected.clone(),
+        };
+
+        if let Some(base_offset) = base_offset {
+            // The base comes earlier in the pack, so it's read already unless it's a delta that is still pending
+            match self.offset_to_sha.get(&(pack, base_offset)) {
+                Some(base) => {
+                    let base = base.clone();
+                    let object = self.undeltify(&base, &decompressed)?;
+                    self.add_object(object, pack, offset, compressed_len, expected)?;
+                }
+                None => {
+                    let missing = self.waiting.get(&(pack, base_offset)).ok_or(ParseError::InvalidDeltaOffset { offset })?.clone();
+                    self.waiting.insert((pack, offset), missing);
+                    self.pending_offsets.entry((pack, base_offset)).or_default().push(delta(decompressed));
+                }
+            }
+            return Ok(());
+        }
+
+        match delta_ref {
+            Some(base) if self.objects.contains_key(base) => {
+                let object = self.undeltify(base, &decompressed)?;
+                self.add_object(object, pack, offset, compressed_len, expected)?;
+            }
+            Some(base) => {
+                // The base comes later in this or another pack, or it isn't in any of them
+                self.waiting.insert((pack, offset), base.to_vec());
+                self.pending.entry(base.to_vec()).or_default().push(delta(decompressed));
+            }
+            None => {
+                let object = PackObject {
+                    obj_type,
+                    data: decompressed,
+                    depth: 0,
+                };
+                self.add_object(object, pack, offset, compressed_len, expected)?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Applies a delta to its base, which has to be read already.
+     */
+    fn undeltify(&mut self, base_sha: &[u8], delta: &[u8]) -> Result<PackObject, ParseError> {
+        let base = self.objects.get(base_sha).unwrap();
+        let object = PackObject {
+            obj_type: base.obj_type.clone(), // We take the type of the base obj
+            data: apply_delta(&base.data, delta)?,
+            depth: base.depth + 1,
+        };
+        self.num_deltas += 1;
+        self.max_delta_depth = self.max_delta_depth.max(object.depth);
+        self.total_delta_depth += object.depth as u64;
+        Ok(object)
+    }
+
+    /**
+     * Stores an object under its sha, along with the deltas that were waiting for it as their base.
+     */
+    fn add_object(&mut self, object: PackObject, pack: usize, offset: usize, compressed_len: usize, expected: Option<Sha>) -> Result<(), ParseError> {
+        let mut queue = vec![(object, pack, offset, compressed_len, expected)];
+
+        while let Some((object, pack, offset, compressed_len, expected)) = queue.pop() {
+            let mut buf = Vec::new();
+            buf.extend(
+                format!("{} {}\0", object.obj_type.git_name().unwrap(), object.data.len())
+                    .as_bytes()
+                    .iter()
+                    .cloned(),
+            );
+            buf.extend(object.data.iter().cloned());
+
+            let sha = self.hash_algorithm.digest(&buf);
+            if let Some(expected) = expected {
+                if expected != sha {
+                    warn(&mut self.warnings, ParseWarning::ShaMismatch {
+                        offset,
+                        expected: ashex(&expected),
+                        got: ashex(&sha),
+                    }, &self.options)?;
+                }
+            }
+            self.locations.push((sha.clone(), offset, compressed_len));
+            if self.objects.contains_key(&sha) {
+                warn(&mut self.warnings, ParseWarning::DuplicateObject {
+                    offset,
+                    sha: ashex(&sha),
+                }, &self.options)?;
+            }
+            self.objects.insert(sha.clone(), object);
+            self.offset_to_sha.insert((pack, offset), sha.clone());
+            self.waiting.remove(&(pack, offset));
+
+            let mut resolved = self.pending.remove(&sha).unwrap_or_default();
+            resolved.extend(self.pending_offsets.remove(&(pack, offset)).unwrap_or_default());
+            for pending in resolved {
+                let object = self.undeltify(&sha, &pending.delta)?;
+                queue.push((object, pending.pack, pending.offset, pending.compressed_len, pending.expected));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Reads the bases that none of the packs have from loose objects, and applies the deltas waiting for them.
+     * A base that isn't there (or can't be read) stays missing.
+     */
+    fn resolve_loose_bases(&mut self, loose_objects: &Path) -> Result<(), ParseError> {
+        let mut bases: Vec<Sha> = self.pending.keys().cloned().collect();
+        bases.sort();
+
+        for base in bases {
+            let hex = ashex(&base);
+            let object = match fs::read(loose_objects.join(&hex[..2]).join(&hex[2..])).ok().and_then(|data| read_loose_object(&data)) {
+                Some(object) => object,
+                None => continue,
+            };
+            // Not part of any pack, so it has no location
+            self.objects.insert(base.clone(), object);
+            for pending in self.pending.remove(&base).unwrap_or_default() {
+                let object = self.undeltify(&base, &pending.delta)?;
+                self.add_object(object, pending.pack, pending.offset, pending.compressed_len, pending.expected)?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Parses the commits and trees once all packs are read.
+     */
+    fn finish(mut self, version: u32) -> Result<ParsePackResult, ParseError> {
+        if let Some(loose_objects) = self.options.loose_objects.clone() {
+            self.resolve_loose_bases(&loose_objects)?;
+        }
+
+        // Whatever is still pending has a base that is in none of the packs
+        let waiting = &self.waiting;
+        let mut missing: Vec<(usize, Sha)> = self.pending.values()
+            .chain(self.pending_offsets.values())
+            .flatten()
+            .map(|delta| (delta.offset, waiting[&(delta.pack, delta.offset)].clone()))
+            .collect();
+        missing.sort();
+        for (offset, base) in &missing {
+            warn(&mut self.warnings, ParseWarning::MissingDeltaBase {
+                offset: *offset,
+                base: ashex(base),
+            }, &self.options)?;
+        }
+        let is_thin = !missing.is_empty();
+
+        let count = |obj_type: PackObjectType| self.objects.values().filter(|object| object.obj_type == obj_type).count();
+        let mut commits = HashMap::<Vec<u8>, GitCommit>::with_capacity(count(PackObjectType::ObjCommit));
+        let mut trees = HashMap::<Vec<u8>, GitTree>::with_capacity(count(PackObjectType::ObjTree));
+        let mut blobs = HashMap::<Vec<u8>, Vec<u8>>::with_capacity(if self.options.retain_blobs { count(PackObjectType::ObjBlob) } else { 0 });
+
+        // In the order they were read, so of several malformed objects it's always the first one that is reported
+        let position: HashMap<&Sha, usize> = self.locations.iter().enumerate().map(|(i, (sha, _, _))| (sha, i)).collect();
+        let mut objects: Vec<(Sha, PackObject)> = self.objects.into_iter().collect();
+        objects.sort_unstable_by(|(a, _), (b, _)| (position.get(a), a).cmp(&(position.get(b), b)));
+
+        for (sha, object) in objects {
+            if object.obj_type == PackObjectType::ObjCommit {
+                commits.insert(sha.clone(), parse_commit(&object.data[..])?);
+            }
+            if object.obj_type == PackObjectType::ObjTree {
+                trees.insert(sha.clone(), parse_tree(&object.data[..], self.hash_algorithm.size(), self.options.max_tree_entries)?);
+            }
+            if object.obj_type == PackObjectType::ObjBlob && self.options.retain_blobs {
+                blobs.insert(sha, object.data);
+            }
+        }
+        set_generations(&mut commits);
+
+        let num_deltas = self.num_deltas;
+        Ok(ParsePackResult {
+            version,
+            hash_algorithm: self.hash_algorithm,
+            stats: PackStats {
+                num_objects: self.num_objects,
+                num_deltas,
+                is_thin,
+                max_delta_depth: self.max_delta_depth,
+                average_delta_depth: if num_deltas > 0 { self.total_delta_depth as f64 / num_deltas as f64 } else { 0.0 },
+            },
+            warnings: self.warnings,
+            locations: self.locations,
+            commits,
+            trees,
+            blobs,
+        })
+    }
+}
+
+/**
+ * Sets GitCommit::generation on all commits, parents first.
+ */
+fn set_generations(commits: &mut HashMap<Sha, GitCommit>) {
+    let mut generations = HashMap::<Sha, u32>::with_capacity(commits.len());
+
+    for start in commits.keys() {
+        // Without recursing, as histories can be very deep
+        let mut stack = vec![(start, false)];
+        while let Some((sha, parents_done)) = stack.pop() {
+            if generations.contains_key(sha) {
+                continue
+            }
+            let parents = commits[sha].parents.iter().filter(|parent| commits.contains_key(*parent));
+            if !parents_done {
+                stack.push((sha, true));
+                stack.extend(parents.filter(|parent| !generations.contains_key(*parent)).map(|parent| (parent, false)));
+                continue
+            }
+            let generation = parents.map(|parent| generations[parent]).max().unwrap_or(0) + 1;
+            generations.insert(sha.clone(), generation);
+        }
+    }
+
+    for (sha, commit) in commits.iter_mut() {
+        commit.generation = generations[sha];
+    }
+}
+
+/**
+ * A loose object as git stores it in .git/objects: "<type> <size>\0<data>", compressed with zlib.
+ * None if it's not a valid loose object.
+ */
+fn read_loose_object(data: &[u8]) -> Option<PackObject> {
+    let mut decompressed = Vec::new();
+    zlib::Decoder::new(data).read_to_end(&mut decompressed).ok()?;
+
+    let nul = decompressed.iter().position(|&byte| byte == 0)?;
+    let header = str::from_utf8(&decompressed[..nul]).ok()?;
+    let (obj_type, size) = header.split_once(' ')?;
+    let obj_type = PackObjectType::from_git_name(obj_type)?;
+    if size.parse::<usize>().ok()? != decompressed.len() - nul - 1 {
+        return None;
+    }
+
+    Some(PackObject {
+        obj_type,
+        data: decompressed.split_off(nul + 1),
+        depth: 0,
+    })
+}
+
+/**
+ * What a delta entry of a pack is based on: the sha of another object, or the offset of an earlier entry.
+ */
+enum DeltaBase<'d> {
+    Sha(&'d [u8]),
+    Offset(usize),
+}
+
+/**
+ * An entry of a pack as it's stored, with its data decompressed but deltas not applied yet.
+ */
+struct PackEntry<'d> {
+    obj_type: PackObjectType,
+    base: Option<DeltaBase<'d>>, // For OFS_DELTA and REF_DELTA entries
+    data: Vec<u8>,
+    compressed_len: usize,
+    len: usize, // Of the whole entry in the pack, header included
+}
+
+/**
+ * Reads the entry at the start of data, which is at offset in the pack.
+ */
+fn read_entry(data: &[u8], offset: usize, hash_size: usize) -> Result<PackEntry<'_>, ParseError> {
+    // First read the n-byte type and len (unpacked) of the obj
+    let mut p = 0;
+    let byte_at = |p: usize| data.get(p).cloned().ok_or(ParseError::TruncatedEntry { offset });
+    let first_byte = byte_at(p)?;
+
+    // Laid out as MSB (more size bytes follow), 3 type bits, 4 low bits of the size
+    let type_bits = (first_byte >> 4) & 0b111;
+    let obj_type = PackObjectType::new(type_bits).ok_or(ParseError::UnknownObjectType { offset, obj_type: type_bits })?;
+    let mut len = (first_byte & 0b1111) as u64;
+
+    let msb = 1 << 7;
+    let mut n = 0;
+    while byte_at(p + n)? & msb != 0 {
+        // While MSB for the current byte not set
+        n += 1;
+        let byte = (byte_at(p + n)? & !msb) as u64; // Without msb
+        let shift = 4 + 7 * (n - 1);
+        if shift >= 64 {
+            // More size bytes than any object could need
+            return Err(ParseError::SizeMismatch { offset });
+        }
+        len |= byte << shift; // Shift bits into place
+    }
+    p += n + 1;
+
+    /*
+        20-byte (32 with SHA-256) base object name if OBJ_REF_DELTA or a negative relative
+        offset from the delta object's position in the pack if this
+        is an OBJ_OFS_DELTA object
+    */
+    let mut base = None;
+    if obj_type == PackObjectType::ObjRefDelta {
+        base = Some(DeltaBase::Sha(data.get(p..p + hash_size).ok_or(ParseError::TruncatedEntry { offset })?));
+        p += hash_size;
+    }
+    if obj_type == PackObjectType::ObjOfsDelta {
+        // 7 bits per byte, most significant first, with one added to all but the last group
+        let mut distance = (byte_at(p)? & !msb) as usize;
+        while byte_at(p)? & msb != 0 {
+            p += 1;
+            distance = (distance + 1).checked_mul(1 << 7).ok_or(ParseError::InvalidDeltaOffset { offset })? | (byte_at(p)? & !msb) as usize;
+        }
+        p += 1;
+        base = Some(DeltaBase::Offset(offset.checked_sub(distance).ok_or(ParseError::InvalidDeltaOffset { offset })?));
+    }
+
+    /*
+    * We actually don't know how long the zlib-compressed object is.
+    * So we just uncompress it and count how many bytes zlib is reading.
+    * This also goes for empty objects, which still have a zlib header and an empty block.
+    */
+    let mut decompressed = Vec::new();
+    let mut counter = ReadCounter::<&[u8]> {
+        inner: data.get(p..).ok_or(ParseError::TruncatedEntry { offset })?,
+        read: 0,
+    };
+
+    zlib::Decoder::new(&mut counter)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| ParseError::ZlibError { offset })?;
+
+    // Our zlib implementation doesn't read the checksum at the end so we need to add 4 bytes
+    let compressed_len = counter.read + 4;
+    p += compressed_len;
+    if p > data.len() {
+        return Err(ParseError::TruncatedEntry { offset });
+    }
+
+    if len != decompressed.len() as u64 {
+        return Err(ParseError::SizeMismatch { offset });
+    }
+
+    Ok(PackEntry {
+        obj_type,
+        base,
+        data: decompressed,
+        compressed_len,
+        len: p,
+    })
+}
+
+/**
+ * Rebuilds an object from its base and a git delta. Fails with ParseError::InvalidDelta instead of
+ * panicking if the delta is malformed or doesn't fit the base.
+ */
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let msb = 1 << 7 as u8;
+
+    let mut p = 0;
+    let invalid = |offset: usize| ParseError::InvalidDelta { offset };
+
+    let source_len = read_delta_size(delta, &mut p)?;
+    let target_len = read_delta_size(delta, &mut p)?;
+    if source_len != base.len() {
+        return Err(invalid(0));
+    }
+
+    // The target length is only trusted as far as the delta could produce it
+    let mut result = Vec::with_capacity(target_len.min(base.len() + delta.len()));
+
+    // The rest of delta is series of instructions
+    while p < delta.len() {
+        let instr_offset = p;
+        let instr = next_delta_byte(delta, &mut p)?;
+
+        if instr == 0 {
+            // Reserved for future use
+            return Err(invalid(instr_offset));
+        } else if instr & msb != 0 {
+            // If msb is set, it's a copy from base instruction.
+            // Bits 0-3 say which bytes of the offset follow, bits 4-6 which bytes of the size.
+            let mut base_offset: usize = 0;
+            let mut copy_size: usize = 0;
+
+            for i in 0..4 {
+                if instr & 1 << i != 0 {
+                    base_offset |= (next_delta_byte(delta, &mut p)? as usize) << (8 * i);
+                }
+            }
+            for i in 0..3 {
+                if instr & 1 << (4 + i) != 0 {
+                    copy_size |= (next_delta_byte(delta, &mut p)? as usize) << (8 * i);
+                }
+            }
+            // A size of 0 stands for 0x10000
+            if copy_size == 0 {
+                copy_size = 0x10000;
+            }
+
+            let copied = base_offset.checked_add(copy_size)
+                .and_then(|end| base.get(base_offset..end))
+                .ok_or_else(|| invalid(instr_offset))?;
+            result.extend_from_slice(copied);
+        } else {
+            // Otherwise it's an instruction to add new data
+            let data_len = instr as usize;
+            let data = delta.get(p..p + data_len).ok_or_else(|| invalid(instr_offset))?;
+            result.extend_from_slice(data);
+            p += data_len;
+        }
+    }
+
+    if result.len() != target_len {
+        return Err(invalid(delta.len()));
+    }
+    Ok(result)
+}
+
+fn next_delta_byte(delta: &[u8], p: &mut usize) -> Result<u8, ParseError> {
+    let byte = *delta.get(*p).ok_or(ParseError::InvalidDelta { offset: *p })?;
+    *p += 1;
+    Ok(byte)
+}
+
+/**
+ * The source and target sizes at the start of a delta are stored little-endian in 7 bits per byte,
+ * with the MSB set on all but the last byte.
+ */
+fn read_delta_size(delta: &[u8], p: &mut usize) -> Result<usize, ParseError> {
+    let mut size: usize = 0;
+    let mut shift = 0;
+    loop {
+        let byte = next_delta_byte(delta, p)?;
+        if shift >= usize::BITS {
+            return Err(ParseError::InvalidDelta { offset: *p - 1 });
+        }
+        size |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/**
+ * Options controlling which commits are counted. Deserializable so JS can pass them as a plain object.
+ */
+#[derive(Default, Deserialize, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ChangeCounterOptions {
+    pub since: Option<i64>, // Only count commits with a date >= since (unix seconds)
+    pub until: Option<i64>, // Only count commits with a date <= until (unix seconds)
+    pub date_field: DateField, // Which date since, until and activity_buckets go by
+    pub max_commits: Option<u32>, // Only count the newest max_commits commits within since and until
+    pub metric: ChangeMetric,
+    pub merge_strategy: MergeStrategy, // Which parents a merge is diffed against
+    pub min_changes: u32, // Leave files with fewer changes out of the tree, and directories that end up empty
+    pub ignore_empty_commits: bool, // Don't count commits that have the same tree as all their parents as activity
+    pub include_shas: bool, // Put the blob sha of each file into its node
+    pub directories_only: bool, // Leave the file nodes out of the tree. Directories still count the changes of their files.
+    pub normalized_heat: bool, // Put the changes per file into each directory node
+    pub max_output_nodes: Option<usize>, // Leave the least changed nodes out of the tree once it would have more, see ChangeCounter::truncation
+    pub lenient: bool, // Skip commits and trees missing from the pack instead of failing, for a partial result
+    pub trim_dir_slashes: bool, // Hand out directory paths as "src/app" instead of "src/app/"
+    pub path_separator: Option<char>, // Separator for the paths handed out instead of "/", e.g. '\\'. Paths passed in still use "/"
+    pub ignore_submodules: bool, // Don't count a submodule pointing to another commit as a change
+    pub ignore_symlinks: bool, // Don't count a symlink pointing somewhere else as a change
+    pub bus_factor: bool, // Keep track of who changed each directory, for the bus_factor of directory nodes
+    pub top_authors: Option<usize>, // Put the authors with the most changes, up to this many, into every node, see ChangeCounter::top_authors
+    pub directory_matrix: bool, // Keep track of the directories each commit changed, see commit_directory_matrix. Takes a lot of memory.
+    pub include_globs: Vec<String>, // If given, only files matching one of these are counted and in the tree, before ignore_globs apply
+    pub ignore_globs: Vec<String>, // Files matching any of these aren't counted and are left out of the tree, see glob::matches
+    pub head_paths_only: bool, // Only count files and directories that are in the head tree, skipping the diffs of everything else
+    pub follow_renames: bool, // Count the changes a file had before it was renamed or moved under the path it ended up at, see ChangeCounter::record_renames
+    pub count_additions: bool, // Count adding a file (by the metric, all its lines) as a change of it and its directories, the files of root commits included
+    pub count_deletions: bool, // Count deleting a file as a change of the directories it was in, whole deleted directories included
+    pub watch_paths: Vec<String>, // If given, only these files (e.g. "src/main.rs") and their directories are counted, see watched_changes
+    pub credit_co_authors: bool, // Count a commit for the people in its Co-authored-by trailers too, in contributors
+    pub exclude_authors: Vec<String>, // Commits by an author email matching any of these (e.g. "dependabot*") aren't counted
+    #[serde(skip)]
+    pub abort: Option<Arc<AtomicBool>>, // Walking the history stops with ProcessError::Aborted once this is set
+}
+
+/**
+ * Which parents the changes of a merge commit are counted against.
+ */
+#[derive(Default, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum MergeStrategy {
+    #[default]
+    AllParents, // Each parent is diffed, so a file differing from two parents counts twice
+    FirstParent, // Only the changes the merge brought into the first parent count
+}
+
+/**
+ * Which of the two dates of a commit to use. They differ once a commit is rebased or cherry-picked.
+ */
+#[derive(Default, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum DateField {
+    #[default]
+    Committer,
+    Author,
+}
+
+/**
+ * What a single modification of a file adds to its count.
+ */
+#[derive(Default, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeMetric {
+    // Every modification counts as one change
+    #[default]
+    Changes,
+    // A modification counts as the number of lines added and removed.
+    // Needs the blobs to be retained, otherwise we fall back to counting one change.
+    #[serde(rename_all = "camelCase")]
+    LineChurn {
+        #[serde(default)]
+        ignore_whitespace: bool, // Lines that only differ in whitespace are considered equal
+    },
+}
+
+impl ChangeCounterOptions {
+    /**
+     * Whether the pack has to be parsed with blobs retained for these options to take effect.
+     */
+    pub fn needs_blobs(&self) -> bool {
+        match self.metric {
+            ChangeMetric::Changes => false,
+            ChangeMetric::LineChurn { .. } => true,
+        }
+    }
+
+    fn in_window(&self, date: i64) -> bool {
+        if let Some(since) = self.since {
+            if date < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if date > until {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn date_of(&self, commit: &GitCommit) -> i64 {
+        match self.date_field {
+            DateField::Committer => commit.date(),
+            DateField::Author => commit.author_date(),
+        }
+    }
+
+    fn is_excluded_author(&self, commit: &GitCommit) -> bool {
+        match &commit.author {
+            Some(author) => self.exclude_authors.iter().any(|pattern| glob::matches(pattern, &author.email)),
+            None => false,
+        }
+    }
+}
+
+/**
+ * Why the history or the head tree couldn't be processed. Missing objects happen with shallow packs.
+ */
+#[derive(Debug, PartialEq)]
+pub enum ProcessError {
+    MissingCommit { sha: String },
+    MissingTree { sha: String },
+    // The abort flag of the ChangeCounterOptions was set
+    Aborted,
+    // A RefSpec that is neither a known ref nor a valid sha
+    UnresolvedRef { spec: String },
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessError::MissingCommit { sha } => write!(f, "Commit {} is not in the pack", sha),
+            ProcessError::MissingTree { sha } => write!(f, "Tree {} is not in the pack", sha),
+            ProcessError::Aborted => write!(f, "Processing was aborted"),
+            ProcessError::UnresolvedRef { spec } => write!(f, "Could not resolve {}", spec),
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {}
+
+fn to_io_error(error: ProcessError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/**
+ * The directories a key is in, from the top, without the root: "/src/app/main.rs" is in "/src/" and "/src/app/".
+ */
+fn directories_of(key: &str) -> impl Iterator<Item = &str> {
+    key.match_indices('/').map(move |(i, _)| &key[..=i]).filter(move |dir| *dir != ROOT && *dir != key)
+}
+
+/**
+ * What the head tree comes down to with pruned directories or max_output_nodes, worked out in one pass
+ * before build_tree or write_tree_json make a node, so they don't have to look below a directory to
+ * know whether it's shown, or make the nodes they leave out anyway.
+ */
+struct TreeSummary {
+    dirs: HashMap<String, DirSummary>, // The directories that have a node, by key, the root included
+    kept: Option<(HashSet<String>, u32)>, // With max_output_nodes, the nodes that are kept and how many aren't, see truncation
+}
+
+#[derive(Clone, Copy)]
+struct DirSummary {
+    num_files: u32,
+    changed: bool,
+    has_children: bool,
+}
+
+/**
+ * The nodes truncation goes by: the first max + 1 of them by most changes, then by key, out of all of them.
+ * No more can be kept, so only those are held on to.
+ */
+struct NodeRanking {
+    max: usize,
+    best: BinaryHeap<(Reverse<u32>, String)>, // The last of them on top
+    count: usize,
+}
+
+impl NodeRanking {
+    fn push(&mut self, num_changes: u32, key: String) {
+        self.count += 1;
+        self.best.push((Reverse(num_changes), key));
+        if self.best.len() > self.max + 1 {
+            self.best.pop();
+        }
+    }
+}
+
+/**
+ * Writes s as a JSON string, escaped the way serde_json does it.
+ */
+fn write_json_string<W: Write>(out: &mut W, s: &str) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            '\u{8}' => out.write_all(b"\\b")?,
+            '\u{c}' => out.write_all(b"\\f")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/**
+ * Counts the changes in the history of a head commit.
+ *
+ * The rankings it hands out (file_changes, directory_changes, top_files, top_directories,
+ * recently_changed and contributors) never depend on hash map order: ties are broken by path,
+ * or by email for contributors, so the same pack and options always give the same output.
+ */
+pub struct ChangeCounter<'a> {
+    pack: &'a ParsePackResult,
+    head: Sha,
+    other_heads: Vec<Sha>, // Walked along with head, but the tree is the one of head
+    options: ChangeCounterOptions,
+    started: bool, // Whether the head commit was queued yet
+    queue: BinaryHeap<(i64, Sha)>, // Commits still to walk, by date
+    processed_commits: HashSet<Vec<u8>>,
+    num_changes: HashMap<String, u32>,
+    commits_touching_path: HashMap<String, u32>, // How many counted commits changed each file and directory, merges only once
+    recorded_paths: (Sha, HashSet<String>), // The paths the commit recorded last touched, as record_changes gets a merge once per parent
+    last_modified: HashMap<String, (Sha, i64)>, // The newest counted commit (by date_field) that changed each file, with that date
+    num_commits: u32,
+    commit_dates: Vec<i64>, // Dates of the counted commits, by the date_field option
+    contributors: HashMap<String, Contributor>, // By author email
+    path_authors: HashMap<String, HashMap<String, u32>>, // Changes by author email of each directory with the bus_factor or top_authors options, and of each file with top_authors
+    directory_matrix: Vec<(Sha, Vec<String>)>, // The directories each commit changed, only with the directory_matrix option
+    deleted: HashMap<String, Sha>, // Files a counted commit deleted, with the newest commit that did
+    first_added: HashMap<String, i64>, // The date (by date_field) of the oldest counted commit that added each file
+    renamed_to: HashMap<String, String>, // With follow_renames, the path each old path of a file ended up at, as far as the walk got
+    head_paths: Option<HashSet<String>>, // The keys of the head tree with head_paths_only, once the walk started
+    trees_diffed: AtomicU32, // How many pairs of different trees diff_changes went into, to see how far watch_paths prunes
+}
+
+/**
+ * What diff_changes found between a commit's tree and a parent's: the counts to add as
+ * `(path, amount)` for the files and their directories, the files that are gone and the ones that are new.
+ */
+#[derive(Default)]
+struct TreeDiff {
+    changes: Vec<(String, u32)>,
+    deleted: Vec<AddedOrDeleted>,
+    added: Vec<AddedOrDeleted>,
+}
+
+/**
+ * A file a diff added or deleted.
+ */
+struct AddedOrDeleted {
+    path: String,
+    sha: Sha, // The blob, to find renames
+    amount: u32, // What it counts as with count_additions or count_deletions, 0 if it isn't counted
+}
+
+/**
+ * A snapshot of a ChangeCounter's walk, so it can be saved (e.g. as JSON) and resumed later with load_state.
+ */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct WalkState {
+    started: bool,
+    queue: BinaryHeap<(i64, Sha)>,
+    processed_commits: HashSet<Vec<u8>>,
+    num_changes: HashMap<String, u32>,
+    commits_touching_path: HashMap<String, u32>,
+    last_modified: HashMap<String, (Sha, i64)>,
+    num_commits: u32,
+    commit_dates: Vec<i64>,
+    contributors: HashMap<String, Contributor>,
+    path_authors: HashMap<String, HashMap<String, u32>>,
+    directory_matrix: Vec<(Sha, Vec<String>)>,
+    deleted: HashMap<String, Sha>,
+    first_added: HashMap<String, i64>,
+    renamed_to: HashMap<String, String>,
+}
+
+/**
+ * The length of the buckets of activity_buckets.
+ */
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum BucketSize {
+    Day,
+    Week, // Starting on Monday
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Contributor {
+    pub name: String,
+    pub email: String,
+    pub commits: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct AuthorChangeCount {
+    pub email: String,
+    pub changes: u32,
+}
+
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct FileChangeCount {
+    pub path: String,
+    pub changes: u32,
+}
+
+/**
+ * See ChangeCounter::file_lifespans.
+ */
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FileLifespan {
+    pub path: String,
+    pub lifespan_commits: u32, // The counted commits since the file was added, including the one that added it
+    pub touch_commits: u32, // How many of them changed it
+}
+
+/**
+ * The key of the root directory. Counting and building the tree both start from it,
+ * so a file at the top level is always keyed "/README.md" on both sides.
+ */
+const ROOT: &str = "/";
+// Up to how many buckets activity_buckets fills in, 500 years of days
+const MAX_ACTIVITY_BUCKETS: i64 = 500 * 366;
+
+/**
+ * Stands for the empty tree a root commit is diffed against with count_additions, which isn't an object in the pack.
+ */
+const EMPTY_TREE_SHA: &[u8] = &[];
+static EMPTY_TREE: GitTree = Vec::new();
+
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct TreeNode {
+	pub name: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub raw_name: Option<String>, // Hex of the name bytes, if the name isn't valid UTF-8
+	pub r#type: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub blob_sha: Option<String>, // Hex sha of a file's blob, only with the include_shas option
+	pub num_changes: u32,
+	pub num_commits: u32, // How many counted commits changed the file or something below the directory
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub has_children: bool, // Set for directories with entries, even when they were left out of children (see children_of)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub bus_factor: Option<u32>, // For directories with the bus_factor option, see ChangeCounter::bus_factor
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub top_authors: Option<Vec<AuthorChangeCount>>, // With the top_authors option, see ChangeCounter::top_authors
+	pub children: Vec<Box<TreeNode>>,
+	// After the children, so write_tree_json can write them once it has gone through the children
+	pub num_files: u32, // 1 for a file, the number of files below a directory that are in the tree (or hidden by directories_only)
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub changed: Option<bool>, // For directories, whether any of those files has changes
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub normalized_heat: Option<f64>, // For directories with the normalized_heat option, numChanges per file
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub truncated: bool, // Set on the root if max_output_nodes left nodes out
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub omitted_nodes: Option<u32>, // On the root of a truncated tree, how many nodes were left out
+}
+
+/**
+ * Everything the explorer shows about a pack, from a single walk of the history.
+ */
+#[derive(Serialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ExplorerResult<'a> {
+    pub tree: TreeNode,
+    pub stats: &'a PackStats,
+    pub num_commits: u32,
+    pub contributors: Vec<Contributor>,
+    pub top_files: Vec<FileChangeCount>,
+    pub commit_graph: Vec<CommitInfo>,
+}
+
+/**
+ * A commit to start from: a sha as hex or as raw bytes, or the name of a ref.
+ * On the JS side that's e.g. `{ "kind": "name", "value": "main" }`.
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "kind", content = "value", rename_all = "camelCase")]
+pub enum RefSpec {
+    Hex(String),
+    Raw(Sha),
+    // A full name ("refs/heads/main", "HEAD") or a branch or tag name ("main", "v1")
+    Name(String),
+}
+
+impl RefSpec {
+    pub fn resolve(&self, refs: &Refs) -> Result<Sha, ProcessError> {
+        match self {
+            RefSpec::Hex(hex) => hex::decode(hex).map_err(|_| ProcessError::UnresolvedRef { spec: hex.clone() }),
+            RefSpec::Raw(sha) => Ok(sha.clone()),
+            RefSpec::Name(name) => [name.clone(), format!("refs/heads/{}", name), format!("refs/tags/{}", name)].iter()
+                .find_map(|name| refs.get(name).cloned())
+                .ok_or_else(|| ProcessError::UnresolvedRef { spec: name.clone() }),
+        }
+    }
+}
+
+/**
+ * The heads to walk and how to count, for ChangeCounter::run.
+ */
+#[derive(Deserialize)]
+pub struct AnalyzeRequest {
+    pub heads: Vec<RefSpec>,
+    #[serde(default)]
+    pub options: ChangeCounterOptions,
+}
+
+/**
+ * Change counts of two trees (e.g. an older cached result and a fresh one) as `(path, new - old)`,
+ * largest increase first. Paths missing from one of the trees count as zero there, directories end with a slash.
+ */
+pub fn compare_results(old: &TreeNode, new: &TreeNode) -> Vec<(String, i64)> {
+    let mut old_counts = HashMap::new();
+    collect_counts(old, "", &mut old_counts);
+    let mut new_counts = HashMap::new();
+    collect_counts(new, "", &mut new_counts);
+
+    let mut deltas: Vec<(String, i64)> = new_counts.iter()
+        .map(|(path, &changes)| (path.clone(), changes as i64 - *old_counts.get(path).unwrap_or(&0) as i64))
+        .collect();
+    for (path, &changes) in &old_counts {
+        if !new_counts.contains_key(path) {
+            deltas.push((path.clone(), -(changes as i64)));
+        }
+    }
+    deltas.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    deltas
+}
+
+fn collect_counts(node: &TreeNode, prefix: &str, counts: &mut HashMap<String, u32>) {
+    for child in &node.children {
+        if child.r#type == "directory" {
+            let path = format!("{}{}/", prefix, child.name);
+            counts.insert(path.clone(), child.num_changes);
+            collect_counts(child, &path, counts);
+        } else {
+            counts.insert(format!("{}{}", prefix, child.name), child.num_changes);
+        }
+    }
+}
+
+impl<'a> ChangeCounter<'a> {
+    pub fn new(pack: &'a ParsePackResult, head_commit: &[u8], options: ChangeCounterOptions) -> ChangeCounter<'a> {
+        ChangeCounter {
+            pack,
+            head: head_commit.to_vec(),
+            other_heads: Vec::new(),
+            options,
+            started: false,
+            queue: BinaryHeap::new(),
+            processed_commits: HashSet::new(),
+            num_changes: HashMap::new(),
+            commits_touching_path: HashMap::new(),
+            recorded_paths: (Vec::new(), HashSet::new()),
+            last_modified: HashMap::new(),
+            num_commits: 0,
+            commit_dates: Vec::new(),
+            contributors: HashMap::new(),
+            path_authors: HashMap::new(),
+            directory_matrix: Vec::new(),
+            deleted: HashMap::new(),
+            first_added: HashMap::new(),
+            renamed_to: HashMap::new(),
+            head_paths: None,
+            trees_diffed: AtomicU32::new(0),
+        }
+    }
+
+    /**
+     * Continues a walk from a saved state. The pack, head and options should be the ones it was saved with.
+     */
+    pub fn load_state(pack: &'a ParsePackResult, head_commit: &[u8], options: ChangeCounterOptions, state: WalkState) -> ChangeCounter<'a> {
+        ChangeCounter {
+            pack,
+            head: head_commit.to_vec(),
+            other_heads: Vec::new(),
+            options,
+            started: state.started,
+            queue: state.queue,
+            processed_commits: state.processed_commits,
+            num_changes: state.num_changes,
+            commits_touching_path: state.commits_touching_path,
+            // A commit is always recorded in one go, so this doesn't need saving
+            recorded_paths: (Vec::new(), HashSet::new()),
+            last_modified: state.last_modified,
+            num_commits: state.num_commits,
+            commit_dates: state.commit_dates,
+            contributors: state.contributors,
+            path_authors: state.path_authors,
+            directory_matrix: state.directory_matrix,
+            deleted: state.deleted,
+            first_added: state.first_added,
+            renamed_to: state.renamed_to,
+            head_paths: None,
+            trees_diffed: AtomicU32::new(0),
+        }
+    }
+
+    pub fn save_state(&self) -> WalkState {
+        WalkState {
+            started: self.started,
+            queue: self.queue.clone(),
+            processed_commits: self.processed_commits.clone(),
+            num_changes: self.num_changes.clone(),
+            commits_touching_path: self.commits_touching_path.clone(),
+            last_modified: self.last_modified.clone(),
+            num_commits: self.num_commits,
+            commit_dates: self.commit_dates.clone(),
+            contributors: self.contributors.clone(),
+            path_authors: self.path_authors.clone(),
+            directory_matrix: self.directory_matrix.clone(),
+            deleted: self.deleted.clone(),
+            first_added: self.first_added.clone(),
+            renamed_to: self.renamed_to.clone(),
+        }
+    }
+
+    /**
+     * Like new, but the histories of all heads are walked together, so a commit reachable from
+     * several of them is counted once. The tree is the one of the first head.
+     */
+    pub fn with_heads(pack: &'a ParsePackResult, heads: &[Sha], options: ChangeCounterOptions) -> ChangeCounter<'a> {
+        let mut counter = ChangeCounter::new(pack, heads.first().map_or(&[][..], |head| &head[..]), options);
+        counter.other_heads = heads.iter().skip(1).cloned().collect();
+        counter
+    }
+
+    /**
+     * Counts the changes in the history of the head commit and builds the tree of the head commit.
+     */
+    pub fn process(pack: &ParsePackResult, head_commit: &[u8], options: ChangeCounterOptions) -> Result<TreeNode, ProcessError> {
+        let request = AnalyzeRequest {
+            heads: vec![RefSpec::Raw(head_commit.to_vec())],
+            options,
+        };
+        ChangeCounter::run(pack, &Refs::new(), request)
+    }
+
+    /**
+     * Resolves the heads of the request against refs, counts the changes in their histories and builds
+     * the tree of the first head.
+     */
+    pub fn run(pack: &ParsePackResult, refs: &Refs, request: AnalyzeRequest) -> Result<TreeNode, ProcessError> {
+        let heads = request.heads.iter().map(|head| head.resolve(refs)).collect::<Result<Vec<Sha>, ProcessError>>()?;
+        let mut comp = ChangeCounter::with_heads(pack, &heads, request.options);
+        comp.walk()?;
+        comp.build_tree()
+    }
+
+    /**
+     * Like process, but with everything else the explorer shows about the history as well.
+     */
+    pub fn analyze(pack: &'a ParsePackResult, head_commit: &[u8], options: ChangeCounterOptions, num_top_files: usize) -> Result<ExplorerResult<'a>, ProcessError> {
+        let mut comp = ChangeCounter::new(pack, head_commit, options);
+        comp.walk()?;
+
+        // The commits reachable from head, ordered by sha like commit_infos
+        let mut commit_graph: Vec<CommitInfo> = comp.processed_commits.iter()
+            .filter_map(|sha| pack.commits.get(sha).map(|commit| CommitInfo::from((sha, commit))))
+            .collect();
+        commit_graph.sort_by(|a, b| a.sha.cmp(&b.sha));
+
+        Ok(ExplorerResult {
+            tree: comp.build_tree()?,
+            stats: &pack.stats,
+            num_commits: comp.num_commits(),
+            contributors: comp.contributors(),
+            top_files: comp.top_files(num_top_files),
+            commit_graph,
+        })
+    }
+
+    pub fn walk(&mut self) -> Result<(), ProcessError> {
+        self.walk_steps(None)?;
+        Ok(())
+    }
+
+    pub fn build_tree(&self) -> Result<TreeNode, ProcessError> {
+        match self.get_commit(&self.head)? {
+            Some(head) => {
+                let summary = self.summarize(&head.tree_sha)?;
+                let mut root = self.build_tree_node(String::from(ROOT), String::from(""), &head.tree_sha, summary.as_ref())?;
+                if let Some((_, omitted)) = summary.as_ref().and_then(|summary| summary.kept.as_ref()) {
+                    root.truncated = true;
+                    root.omitted_nodes = Some(*omitted);
+                }
+                Ok(root)
+            }
+            None => Ok(TreeNode {
+                name: String::from(""),
+                raw_name: None,
+                r#type: String::from("directory"),
+                blob_sha: None,
+                num_changes: 0,
+                num_commits: 0,
+                has_children: false,
+                bus_factor: None,
+                top_authors: None,
+                children: vec![],
+                num_files: 0,
+                changed: Some(false),
+                normalized_heat: self.normalized_heat(0, 0),
+                truncated: false,
+                omitted_nodes: None,
+            }),
+        }
+    }
+
+    /**
+     * Paths are keyed with a leading slash internally ("/src/main.rs", "/src/"), but handed out relative to the root ("src/main.rs")
+     * and with the path_separator option applied.
+     */
+    fn output_path(&self, key: &str) -> String {
+        let mut path = key.trim_start_matches('/');
+        if self.options.trim_dir_slashes {
+            path = path.trim_end_matches('/');
+        }
+        match self.options.path_separator {
+            Some(separator) => path.replace('/', &separator.to_string()),
+            None => path.to_owned(),
+        }
+    }
+
+    /**
+     * Builds the tree of any tree object in the pack, without looking at the history, so all counts are zero.
+     * For browsing a snapshot like a file manager.
+     */
+    pub fn build_tree_for(pack: &ParsePackResult, tree_sha: &[u8]) -> Result<TreeNode, ProcessError> {
+        let browser = ChangeCounter::new(pack, &[], ChangeCounterOptions::default());
+        browser.build_tree_node(String::from(ROOT), String::from(""), tree_sha, None)
+    }
+
+    /**
+     * Looks up a commit. In lenient mode a missing commit is None, so the caller can skip it.
+     */
+    fn get_commit(&self, sha: &[u8]) -> Result<Option<&'a GitCommit>, ProcessError> {
+        match self.pack.commits.get(sha) {
+            Some(commit) => Ok(Some(commit)),
+            None if self.options.lenient => Ok(None),
+            None => Err(ProcessError::MissingCommit { sha: ashex(sha) }),
+        }
+    }
+
+    /**
+     * Looks up a tree. In lenient mode a missing tree is None, so the caller can skip it.
+     */
+    fn get_tree(&self, sha: &[u8]) -> Result<Option<&'a GitTree>, ProcessError> {
+        match self.pack.trees.get(sha) {
+            Some(tree) => Ok(Some(tree)),
+            None if self.options.lenient => Ok(None),
+            None => Err(ProcessError::MissingTree { sha: ashex(sha) }),
+        }
+    }
+
+    /**
+     * How many commits were counted.
+     */
+    pub fn num_commits(&self) -> u32 {
+        self.num_commits
+    }
+
+    /**
+     * The number of counted commits per day or week as `(bucket start, commits)`, oldest first.
+     * Buckets are in UTC and run from the oldest to the newest commit, with zero for buckets without commits.
+     * If those are more than MAX_ACTIVITY_BUCKETS buckets apart, e.g. because of a bogus date, only the buckets
+     * with commits are listed. A commit too close to the smallest date for its bucket to start is left out.
+     */
+    pub fn activity_buckets(&self, bucket: BucketSize) -> Vec<(i64, u32)> {
+        const DAY: i64 = 24 * 60 * 60;
+        let (length, offset) = match bucket {
+            BucketSize::Day => (DAY, 0),
+            BucketSize::Week => (7 * DAY, 4 * DAY), // The epoch is on a Thursday
+        };
+        // Takes the remainder before the offset, so no date overflows
+        let bucket_start = |date: i64| date.checked_sub((date.rem_euclid(length) - offset).rem_euclid(length));
+
+        let mut counts = BTreeMap::<i64, u32>::new();
+        for start in self.commit_dates.iter().filter_map(|&date| bucket_start(date)) {
+            *counts.entry(start).or_insert(0) += 1;
+        }
+        let (first, last) = match (counts.keys().next(), counts.keys().next_back()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => return vec![],
+        };
+        match last.checked_sub(first) {
+            Some(span) if span / length < MAX_ACTIVITY_BUCKETS => (0..=span / length)
+                .map(|i| first + i * length)
+                .map(|start| (start, counts.get(&start).copied().unwrap_or(0)))
+                .collect(),
+            _ => counts.into_iter().collect(),
+        }
+    }
+
+    /**
+     * Authors of the counted commits, most commits first. Authors with the same count are ordered by email.
+     */
+    pub fn contributors(&self) -> Vec<Contributor> {
+        let mut contributors: Vec<Contributor> = self.contributors.values().cloned().collect();
+        contributors.sort_by(|a, b| b.commits.cmp(&a.commits).then_with(|| a.email.cmp(&b.email)));
+        contributors
+    }
+
+    /**
+     * The n files whose last change is the most recent, newest first, with the commit and date of that change.
+     * Files changed at the same time are ordered by path.
+     */
+    pub fn recently_changed(&self, n: usize) -> Vec<(String, Sha, i64)> {
+        let mut files: Vec<(String, Sha, i64)> = self.last_modified.iter()
+            .map(|(path, (commit, date))| (self.output_path(path), commit.clone(), *date))
+            .collect();
+        files.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        files.truncate(n);
+        files
+    }
+
+    /**
+     * Every file that was changed, most changed first. Files with the same count are ordered by path.
+     */
+    pub fn file_changes(&self) -> Vec<FileChangeCount> {
+        let mut files: Vec<FileChangeCount> = self.num_changes.iter()
+            .filter(|(path, _)| !path.ends_with('/'))
+            .map(|(path, changes)| FileChangeCount {
+                path: self.output_path(path),
+                changes: *changes,
+            })
+            .collect();
+        files.sort_by(|a, b| b.changes.cmp(&a.changes).then_with(|| a.path.cmp(&b.path)));
+        files
+    }
+
+    /**
+     * Every directory below the root that had changes, most changed first, ordered by path on ties.
+     */
+    pub fn directory_changes(&self) -> Vec<FileChangeCount> {
+        let mut dirs: Vec<FileChangeCount> = self.num_changes.iter()
+            .filter(|(path, _)| path.ends_with('/') && path.as_str() != ROOT)
+            .map(|(path, changes)| FileChangeCount {
+                path: self.output_path(path),
+                changes: *changes,
+            })
+            .collect();
+        dirs.sort_by(|a, b| b.changes.cmp(&a.changes).then_with(|| a.path.cmp(&b.path)));
+        dirs
+    }
+
+    /**
+     * The count of a file or directory by its path relative to the root.
+     * Directories can be given with or without a trailing slash, the root is "".
+     */
+    pub fn changes_for(&self, path: &str) -> Option<u32> {
+        let key = format!("{}{}", ROOT, path.trim_start_matches('/'));
+        self.num_changes.get(&key)
+            .or_else(|| self.num_changes.get(&format!("{}/", key)))
+            .cloned()
+    }
+
+    /**
+     * The counts of the watch_paths, zero for the ones that were never changed.
+     */
+    pub fn watched_changes(&self) -> HashMap<String, u32> {
+        self.options.watch_paths.iter()
+            .map(|path| (path.clone(), self.changes_for(path).unwrap_or(0)))
+            .collect()
+    }
+
+    /**
+     * The k most changed files, ordered like file_changes.
+     */
+    pub fn top_files(&self, k: usize) -> Vec<FileChangeCount> {
+        let mut files = self.file_changes();
+        files.truncate(k);
+        files
+    }
+
+    /**
+     * The k most changed directories, ordered like directory_changes. A directory's count is the sum over all files below it.
+     */
+    pub fn top_directories(&self, k: usize) -> Vec<FileChangeCount> {
+        let mut dirs = self.directory_changes();
+        dirs.truncate(k);
+        dirs
+    }
+
+    /**
+     * Every blob in the head tree with the paths of the files that have it as their contents.
+     * Only the tree is needed for this, not the blobs themselves.
+     */
+    pub fn blob_paths(&self) -> Result<HashMap<Sha, Vec<String>>, ProcessError> {
+        let mut paths = self.blob_keys()?;
+        for keys in paths.values_mut() {
+            for key in keys.iter_mut() {
+                *key = self.output_path(key);
+            }
+        }
+        Ok(paths)
+    }
+
+    /**
+     * Like blob_paths, but with the paths as internal keys.
+     */
+    fn blob_keys(&self) -> Result<HashMap<Sha, Vec<String>>, ProcessError> {
+        let mut paths: HashMap<Sha, Vec<String>> = HashMap::new();
+        if let Some(head) = self.get_commit(&self.head)? {
+            self.walk_keys(&head.tree_sha, ROOT, &mut |key, entry| {
+                if entry.object_type() == "blob" {
+                    paths.entry(entry.sha.clone()).or_default().push(key.to_owned());
+                }
+            })?;
+        }
+        Ok(paths)
+    }
+
+    /**
+     * Files in the head tree that none of the counted commits changed, ordered by path.
+     * Ignored files are left out.
+     */
+    pub fn stale_files(&self) -> Result<Vec<String>, ProcessError> {
+        let mut files: Vec<String> = self.blob_keys()?.into_values()
+            .flatten()
+            .filter(|key| !self.is_ignored(key) && *self.num_changes.get(key).unwrap_or(&0) == 0)
+            .map(|key| self.output_path(&key))
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /**
+     * How many different file contents there are in the head tree.
+     */
+    pub fn unique_blobs(&self) -> Result<usize, ProcessError> {
+        Ok(self.blob_keys()?.len())
+    }
+
+    /**
+     * Files in the head tree whose contents look binary, ordered by path. Like git, a file is binary
+     * if there's a NUL byte in its first 8000 bytes. Needs the blobs to be retained, files whose
+     * contents weren't kept are left out. Ignored files are left out too.
+     */
+    pub fn binary_files(&self) -> Result<Vec<String>, ProcessError> {
+        let mut files: Vec<String> = self.blob_keys()?.into_iter()
+            .filter(|(sha, _)| self.pack.blobs.get(sha).is_some_and(|data| looks_binary(data)))
+            .flat_map(|(_, keys)| keys)
+            .filter(|key| !self.is_ignored(key))
+            .map(|key| self.output_path(&key))
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /**
+     * Calls visit for every entry below a tree, depth first in the order of the tree, with its path
+     * as it's handed out ("src/", "src/main.rs"). Directories come before their contents.
+     */
+    pub fn walk_tree<F: FnMut(&str, &GitTreeEntry)>(&self, tree_sha: &[u8], mut visit: F) -> Result<(), ProcessError> {
+        self.walk_keys(tree_sha, ROOT, &mut |key, entry| visit(&self.output_path(key), entry))
+    }
+
+    /**
+     * How many files there are in the tree of a commit, without building any nodes. Counts what's in the pack
+     * even if subtrees are missing, and is 0 if the commit is.
+     */
+    pub fn file_count(&self, commit: &[u8]) -> usize {
+        let commit = match self.pack.commits.get(commit) {
+            Some(commit) => commit,
+            None => return 0,
+        };
+        let mut count = 0;
+        let _ = self.walk_entries(&commit.tree_sha, ROOT, true, &mut |_, entry| {
+            if entry.object_type() == "blob" {
+                count += 1;
+            }
+        });
+        count
+    }
+
+    /**
+     * Like walk_tree, but with the paths as internal keys below prefix.
+     */
+    fn walk_keys<F: FnMut(&str, &GitTreeEntry)>(&self, tree_sha: &[u8], prefix: &str, visit: &mut F) -> Result<(), ProcessError> {
+        self.walk_entries(tree_sha, prefix, self.options.lenient, visit)
+    }
+
+    /**
+     * Like walk_keys, but with skip_missing trees missing from the pack are left out instead of failing.
+     */
+    fn walk_entries<F: FnMut(&str, &GitTreeEntry)>(&self, tree_sha: &[u8], prefix: &str, skip_missing: bool, visit: &mut F) -> Result<(), ProcessError> {
+        let tree = match self.pack.trees.get(tree_sha) {
+            Some(tree) => tree,
+            None if skip_missing => return Ok(()),
+            None => return Err(ProcessError::MissingTree { sha: ashex(tree_sha) }),
+        };
+
+        for entry in tree {
+            if entry.is_dir {
+                let key = format!("{}{}/", prefix, entry.name);
+                visit(&key, entry);
+                self.walk_entries(&entry.sha, &key, skip_missing, visit)?;
+            } else {
+                visit(&format!("{}{}", prefix, entry.name), entry);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Whether a file is counted with the watch_paths option. Without it every file is.
+     */
+    fn is_watched(&self, key: &str) -> bool {
+        let path = key.trim_start_matches('/');
+        self.options.watch_paths.is_empty() || self.options.watch_paths.iter().any(|watched| watched.trim_matches('/') == path)
+    }
+
+    /**
+     * Whether a directory (by its key like "/src/") has a watched file below it, so the diff has to go into it.
+     */
+    fn watches_below(&self, dir_key: &str) -> bool {
+        let dir = dir_key.trim_start_matches('/');
+        self.options.watch_paths.is_empty() || self.options.watch_paths.iter().any(|watched| watched.trim_start_matches('/').starts_with(dir))
+    }
+
+    /**
+     * Whether a file is left out: with include_globs, files matching none of them are, and then
+     * files matching one of the ignore_globs. Patterns without a slash are matched against the
+     * file name ("*.lock"), others against the path from the root ("src/gen_?.rs").
+     */
+    fn is_ignored(&self, key: &str) -> bool {
+        let path = key.trim_start_matches('/');
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let matches_any = |patterns: &[String]| patterns.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob::matches(pattern, path)
+            } else {
+                glob::matches(pattern, name)
+            }
+        });
+
+        if !self.options.include_globs.is_empty() && !matches_any(&self.options.include_globs) {
+            return true;
+        }
+        matches_any(&self.options.ignore_globs)
+    }
+
+    fn num_commits_of(&self, path: &str) -> u32 {
+        *self.commits_touching_path.get(path).unwrap_or(&0)
+    }
+
+    fn count_change(&mut self, path: String, amount: u32) {
+        let previous = self.num_changes.get(&path).unwrap_or(&0);
+        self.num_changes.insert(path, previous + amount);
+    }
+
+    /**
+     * How much a modification of a file from blob a to blob b counts according to the metric.
+     */
+    fn change_amount(&self, a: &[u8], b: &[u8]) -> u32 {
+        match self.options.metric {
+            ChangeMetric::Changes => 1,
+            ChangeMetric::LineChurn { ignore_whitespace } => {
+                match (self.pack.blobs.get(a), self.pack.blobs.get(b)) {
+                    (Some(a), Some(b)) => linediff::line_churn(a, b, ignore_whitespace),
+                    _ => 1,
+                }
+            }
+        }
+    }
+
+    /**
+     * What adding or deleting a file counts as, with counted being whether the options count that at all.
+     * By the line churn metric, that's all the lines of the file.
+     */
+    fn whole_file_amount(&self, counted: bool, entry: &GitTreeEntry) -> u32 {
+        let kind = entry.kind();
+        if !counted
+            || (self.options.ignore_submodules && kind == EntryKind::Submodule)
+            || (self.options.ignore_symlinks && kind == EntryKind::Symlink) {
+            return 0
+        }
+        match self.options.metric {
+            ChangeMetric::Changes => 1,
+            ChangeMetric::LineChurn { ignore_whitespace } => {
+                match self.pack.blobs.get(&entry.sha) {
+                    Some(blob) => linediff::line_churn(&[], blob, ignore_whitespace),
+                    None => 1,
+                }
+            }
+        }
+    }
+
+    /**
+     * Collects how much each path changed between two trees as `(path, amount)`, directories included.
+     * Only reads, so the diffs of different commits can be done in parallel.
+     */
+    fn diff_changes(&self, from_tree: &[u8], to_tree: &[u8], prefix: Vec<String>, diff: &mut TreeDiff) -> Result<(), ProcessError> {
+        if from_tree == to_tree {
+            // Trees are identical
+            return Ok(())
+        }
+        self.trees_diffed.fetch_add(1, Ordering::Relaxed);
+
+        let a = if from_tree == EMPTY_TREE_SHA { Some(&EMPTY_TREE) } else { self.get_tree(from_tree)? };
+        let (a, b) = match (a, self.get_tree(to_tree)?) {
+            (Some(a), Some(b)) => (a, b),
+            _ => return Ok(()),
+        };
+
+        for change in diff_trees(a, b) {
+            if change.kind == ChangeKind::Deleted || change.kind == ChangeKind::Renamed {
+                // Gone from its old path, with everything below it if it's a directory
+                let old_path = format!("{}{}", prefix.last().unwrap(), change.old_path.as_ref().unwrap_or(&change.path));
+                if change.is_dir {
+                    if !self.watches_below(&format!("{}/", old_path)) {
+                        continue
+                    }
+                    let _ = self.walk_entries(change.old_sha.as_ref().unwrap(), &format!("{}/", old_path), true, &mut |key, entry| {
+                        if !entry.is_dir && !self.is_ignored(key) && self.is_watched(key) {
+                            let amount = self.whole_file_amount(self.options.count_deletions, entry);
+                            diff.deleted.push(AddedOrDeleted { path: key.to_owned(), sha: entry.sha.clone(), amount });
+                        }
+                    });
+                } else if !self.is_ignored(&old_path) && self.is_watched(&old_path) {
+                    let name = change.old_path.as_ref().unwrap_or(&change.path);
+                    let amount = a.iter().find(|entry| entry.name == *name && !entry.is_dir)
+                        .map_or(0, |entry| self.whole_file_amount(self.options.count_deletions, entry));
+                    diff.deleted.push(AddedOrDeleted { path: old_path, sha: change.old_sha.clone().unwrap(), amount });
+                }
+            }
+            if change.kind == ChangeKind::Added || change.kind == ChangeKind::Renamed {
+                // New at its path, with everything below it if it's a directory
+                let path = format!("{}{}", prefix.last().unwrap(), change.path);
+                if change.is_dir {
+                    if self.watches_below(&format!("{}/", path)) && self.diffs_path(&format!("{}/", path)) {
+                        let _ = self.walk_entries(change.new_sha.as_ref().unwrap(), &format!("{}/", path), true, &mut |key, entry| {
+                            if !entry.is_dir && !self.is_ignored(key) && self.is_watched(key) {
+                                let amount = self.whole_file_amount(self.options.count_additions, entry);
+                                diff.added.push(AddedOrDeleted { path: key.to_owned(), sha: entry.sha.clone(), amount });
+                            }
+                        });
+                    }
+                } else if !self.is_ignored(&path) && self.is_watched(&path) && self.diffs_path(&path) {
+                    let name = &change.path;
+                    let amount = b.iter().find(|entry| entry.name == *name && !entry.is_dir)
+                        .map_or(0, |entry| self.whole_file_amount(self.options.count_additions, entry));
+                    diff.added.push(AddedOrDeleted { path, sha: change.new_sha.clone().unwrap(), amount });
+                }
+            }
+            if change.kind != ChangeKind::Modified {
+                // Additions and deletions are counted by record_changes, if at all
+                continue
+            }
+            let old_sha = change.old_sha.unwrap();
+            let new_sha = change.new_sha.unwrap();
+
+            if change.is_dir {
+                // There were changes in the dir
+                let dir_path = format!("{}{}/", prefix.last().unwrap(), change.path);
+                if !self.watches_below(&dir_path) || !self.diffs_path(&dir_path) {
+                    continue
+                }
+                let mut new_prefix = prefix.clone();
+                new_prefix.push(dir_path);
+                self.diff_changes(&old_sha, &new_sha, new_prefix, diff)?;
+            } else {
+                let path = format!("{}{}", prefix.last().unwrap(), change.path);
+                if self.is_ignored(&path) || !self.is_watched(&path) || !self.diffs_path(&path) {
+                    continue
+                }
+                // A submodule is counted like a file whose contents is the commit it points to
+                let name = &change.path;
+                let kind = b.iter().find(|entry| entry.name == *name && !entry.is_dir).map(|entry| entry.kind());
+                if (self.options.ignore_submodules && kind == Some(EntryKind::Submodule))
+                    || (self.options.ignore_symlinks && kind == Some(EntryKind::Symlink)) {
+                    continue
+                }
+                let amount = self.change_amount(&old_sha, &new_sha);
+                if amount == 0 {
+                    continue
+                }
+                for dir in &prefix {
+                    diff.changes.push((dir.to_string(), amount));
+                }
+                diff.changes.push((path, amount));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Pairs the files a diff deleted with the ones it added with the same contents, as git does with -M
+     * but only for exact matches. The older commits the walk gets to later changed the file under its
+     * old path, and with follow_renames those changes count for the path it ended up at. The ignore
+     * and watch filters still go by the path a file had in the commit being diffed.
+     * Returns the old and new paths of the renamed files, which count_additions and count_deletions skip.
+     */
+    fn record_renames(&mut self, deleted: &[AddedOrDeleted], added: &[AddedOrDeleted]) -> HashSet<String> {
+        let name = |key: &str| key.rsplit('/').next().unwrap_or("").to_owned();
+        let mut unpaired: Vec<&AddedOrDeleted> = deleted.iter().collect();
+        let mut renamed = HashSet::new();
+        for file in added {
+            // Of several files with the same contents (e.g. empty ones), the one with the same name is the likeliest
+            let index = unpaired.iter().position(|old| old.sha == file.sha && name(&old.path) == name(&file.path))
+                .or_else(|| unpaired.iter().position(|old| old.sha == file.sha));
+            if let Some(index) = index {
+                let old = unpaired.remove(index);
+                let target = self.renamed_to.get(&file.path).cloned().unwrap_or_else(|| file.path.clone());
+                self.renamed_to.insert(old.path.clone(), target);
+                renamed.insert(old.path.clone());
+                renamed.insert(file.path.clone());
+            }
+        }
+        renamed
+    }
+
+    /**
+     * The changes of a diff with every file under the path it was renamed to, counted for that path's directories.
+     */
+    fn followed_changes(&self, changes: Vec<(String, u32)>) -> Vec<(String, u32)> {
+        let mut followed = Vec::with_capacity(changes.len());
+        for (path, amount) in changes {
+            if path.ends_with('/') {
+                continue
+            }
+            let path = self.renamed_to.get(&path).cloned().unwrap_or(path);
+            if !self.is_at_head(&path) {
+                continue
+            }
+            followed.push((String::from(ROOT), amount));
+            followed.extend(directories_of(&path).map(|dir| (dir.to_owned(), amount)));
+            followed.push((path, amount));
+        }
+        followed
+    }
+
+    fn record_changes(&mut self, commit_sha: &[u8], diff: TreeDiff) {
+        let TreeDiff { mut changes, deleted, added } = diff;
+        let mut renamed = HashSet::new();
+        if self.options.follow_renames {
+            renamed = self.record_renames(&deleted, &added);
+            changes = self.followed_changes(changes);
+        }
+        let files = deleted.iter().map(|file| (file, false)).chain(added.iter().map(|file| (file, true)));
+        for (file, is_added) in files.filter(|(file, _)| file.amount > 0 && !renamed.contains(&file.path)) {
+            // A deleted file is gone from head, but the directories it was in may still be there.
+            // An added file that was renamed later on (so only with follow_renames) counts under its new path.
+            let path = self.renamed_to.get(&file.path).filter(|_| is_added).unwrap_or(&file.path);
+            let keys = std::iter::once(ROOT).chain(directories_of(path)).chain(std::iter::once(path.as_str()));
+            changes.extend(keys.filter(|key| self.is_at_head(key)).map(|key| (key.to_owned(), file.amount)));
+        }
+        for file in deleted {
+            // The walk goes newest first, so the first commit seen deleting a file is the newest one
+            self.deleted.entry(file.path).or_insert_with(|| commit_sha.to_vec());
+        }
+        let pack = self.pack;
+        let date = self.options.date_of(pack.commits.get(commit_sha).unwrap());
+        for file in added {
+            let path = self.renamed_to.get(&file.path).cloned().unwrap_or(file.path);
+            let first = self.first_added.entry(path).or_insert(date);
+            *first = date.min(*first);
+        }
+        let author = pack.commits.get(commit_sha).unwrap().author.as_ref().filter(|_| self.options.bus_factor || self.options.top_authors.is_some());
+        if self.options.directory_matrix {
+            // The directories (but the root) go into the row of the commit, which merges add to once per parent
+            let dirs = changes.iter().filter(|(path, _)| path.ends_with('/') && path != ROOT).map(|(path, _)| path.clone());
+            match self.directory_matrix.last_mut() {
+                Some((sha, row)) if sha == commit_sha => row.extend(dirs),
+                _ => self.directory_matrix.push((commit_sha.to_vec(), dirs.collect())),
+            }
+            let row = &mut self.directory_matrix.last_mut().unwrap().1;
+            row.sort();
+            row.dedup();
+        }
+        if self.recorded_paths.0 != commit_sha {
+            self.recorded_paths = (commit_sha.to_vec(), HashSet::new());
+        }
+        for (path, amount) in changes {
+            if self.recorded_paths.1.insert(path.clone()) {
+                *self.commits_touching_path.entry(path.clone()).or_default() += 1;
+            }
+            let is_dir = path.ends_with('/');
+            if !is_dir {
+                self.record_last_modified(&path, commit_sha);
+            }
+            if let Some(author) = author.filter(|_| is_dir || self.options.top_authors.is_some()) {
+                *self.path_authors.entry(path.clone()).or_default().entry(author.email.clone()).or_default() += amount;
+            }
+            self.count_change(path, amount);
+        }
+    }
+    
+    fn record_last_modified(&mut self, path: &str, commit_sha: &[u8]) {
+        // Of commits with the same date the first one walked is kept, which is the one with the largest sha
+        let date = self.options.date_of(self.pack.commits.get(commit_sha).unwrap());
+        let newer = match self.last_modified.get(path) {
+            Some((_, previous)) => date > *previous,
+            None => true,
+        };
+        if newer {
+            self.last_modified.insert(path.to_owned(), (commit_sha.to_vec(), date));
+        }
+    }
+
+    /**
+     * A commit that didn't change anything, like one made with `git commit --allow-empty`.
+     */
+    fn is_empty_commit(&self, commit: &GitCommit) -> Result<bool, ProcessError> {
+        if commit.parents.is_empty() {
+            return Ok(false)
+        }
+        for parent_sha in &commit.parents {
+            match self.get_commit(parent_sha)? {
+                Some(parent) if parent.tree_sha == commit.tree_sha => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    fn count_commit(&mut self, commit: &GitCommit) {
+        self.num_commits += 1;
+        self.commit_dates.push(self.options.date_of(commit));
+
+        if let Some(author) = &commit.author {
+            let contributor = self.contributors.entry(author.email.clone()).or_insert_with(|| Contributor {
+                name: author.name.clone(), // We walk newest first, so this is the most recent name
+                email: author.email.clone(),
+                commits: 0,
+            });
+            contributor.commits += 1;
+        }
+        if self.options.credit_co_authors {
+            let author_email = commit.author.as_ref().map(|author| author.email.as_str());
+            let mut credited = HashSet::new();
+            for (name, email) in commit.co_authors() {
+                // Someone listed twice, or as the author too, still gets the commit once
+                if Some(email.as_str()) == author_email || !credited.insert(email.clone()) {
+                    continue
+                }
+                let contributor = self.contributors.entry(email.clone()).or_insert_with(|| Contributor {
+                    name,
+                    email,
+                    commits: 0,
+                });
+                contributor.commits += 1;
+            }
+        }
+    }
+
+    /**
+     * With head_paths_only, collects what is_at_head goes by, unless that was done already.
+     */
+    fn collect_head_paths(&mut self) -> Result<(), ProcessError> {
+        if !self.options.head_paths_only || self.head_paths.is_some() {
+            return Ok(());
+        }
+        let mut paths = HashSet::new();
+        paths.insert(String::from(ROOT));
+        if let Some(head) = self.get_commit(&self.head)? {
+            self.walk_keys(&head.tree_sha, ROOT, &mut |key, _| {
+                paths.insert(key.to_owned());
+            })?;
+        }
+        self.head_paths = Some(paths);
+        Ok(())
+    }
+
+    /**
+     * Whether diff_changes goes on with a path, which it can leave out with head_paths_only if it isn't
+     * at head. With follow_renames it could be the old path of a file that is, so then that's only
+     * checked once record_changes followed the renames.
+     */
+    fn diffs_path(&self, key: &str) -> bool {
+        self.options.follow_renames || self.is_at_head(key)
+    }
+
+    /**
+     * Whether a file or directory is counted with the head_paths_only option. Without it everything is.
+     */
+    fn is_at_head(&self, key: &str) -> bool {
+        match &self.head_paths {
+            Some(paths) => paths.contains(key),
+            None => true,
+        }
+    }
+
+    /**
+     * Walks at most max_steps more commits of the history, or all of them without a limit.
+     * Returns whether the walk is done, so it can be spread over several calls.
+     */
+    pub fn walk_steps(&mut self, max_steps: Option<usize>) -> Result<bool, ProcessError> {
+        self.collect_head_paths()?;
+        let mut diffs = Vec::new();
+        let done = self.walk_commits(max_steps, &mut diffs)?;
+        for (commit_sha, from_tree, to_tree) in diffs {
+            let mut diff = TreeDiff::default();
+            self.diff_changes(from_tree, to_tree, vec![String::from(ROOT)], &mut diff)?;
+            self.record_changes(&commit_sha, diff);
+        }
+        Ok(done)
+    }
+
+    /**
+     * Like walk, but the trees of the counted commits are diffed on all cores.
+     * Gives exactly the same counts, as the results are recorded in the order walk would.
+     */
+    #[cfg(feature = "parallel")]
+    pub fn walk_parallel(&mut self) -> Result<(), ProcessError> {
+        use rayon::prelude::*;
+
+        self.collect_head_paths()?;
+        let mut diffs = Vec::new();
+        self.walk_commits(None, &mut diffs)?;
+        let counter = &*self;
+        let changes = diffs.par_iter()
+            .map(|(_, from_tree, to_tree)| {
+                let mut diff = TreeDiff::default();
+                counter.diff_changes(from_tree, to_tree, vec![String::from(ROOT)], &mut diff)?;
+                Ok(diff)
+            })
+            .collect::<Result<Vec<_>, ProcessError>>()?;
+        for ((commit_sha, _, _), diff) in diffs.iter().zip(changes) {
+            self.record_changes(commit_sha, diff);
+        }
+        Ok(())
+    }
+
+    /**
+     * Walks the commits and counts them, leaving the tree diffs of the counted ones as
+     * `(commit, parent tree, tree)` in diffs for the caller.
+     */
+    fn walk_commits(&mut self, max_steps: Option<usize>, diffs: &mut Vec<(Sha, &'a [u8], &'a [u8])>) -> Result<bool, ProcessError> {
+        // Walk newest to oldest, so the history is visited in the order it was committed in
+        if !self.started {
+            self.started = true;
+            let heads: Vec<Sha> = std::iter::once(self.head.clone()).chain(self.other_heads.iter().cloned()).collect();
+            for head_sha in heads {
+                if let Some(head) = self.get_commit(&head_sha)? {
+                    self.queue.push((head.date(), head_sha));
+                }
+            }
+        }
+
+        let mut steps = 0;
+        while !self.queue.is_empty() {
+            if Some(steps) == max_steps {
+                return Ok(false)
+            }
+            if aborted(&self.options.abort) {
+                return Err(ProcessError::Aborted)
+            }
+
+            let (_, commit_sha) = self.queue.pop().unwrap();
+            if !self.processed_commits.insert(commit_sha.clone()) {
+                continue
+            }
+            steps += 1;
+
+            // Everything in the queue was looked up before
+            let commit = self.pack.commits.get(&commit_sha).unwrap();
+            // Commits outside the window are not counted, but we still walk through them to reach older ones
+            let counted = self.options.in_window(self.options.date_of(commit))
+                && !self.options.is_excluded_author(commit)
+                && self.num_commits < self.options.max_commits.unwrap_or(u32::MAX)
+                && !(self.options.ignore_empty_commits && self.is_empty_commit(commit)?);
+            if counted {
+                self.count_commit(commit);
+            }
+
+            for (i, parent_sha) in commit.parents.iter().enumerate() {
+                let parent = match self.get_commit(parent_sha)? {
+                    Some(parent) => parent,
+                    None => continue,
+                };
+                if counted && (i == 0 || self.options.merge_strategy == MergeStrategy::AllParents) {
+                    diffs.push((commit_sha.clone(), &parent.tree_sha, &commit.tree_sha));
+                }
+                self.queue.push((parent.date(), parent_sha.clone()));
+            }
+            if counted && commit.parents.is_empty() && self.options.count_additions {
+                // Everything in a root commit was added by it
+                diffs.push((commit_sha.clone(), EMPTY_TREE_SHA, &commit.tree_sha));
+            }
+        }
+        Ok(true)
+    }
+
+    /**
+     * The direct children of a directory of the head tree (e.g. "src/", "" for the root) with their change counts,
+     * but without their own children, for expanding the tree one level at a time. Empty if there is no such directory.
+     */
+    pub fn children_of(&self, path: &str) -> Result<Vec<TreeNode>, ProcessError> {
+        let head = match self.get_commit(&self.head)? {
+            Some(head) => head,
+            None => return Ok(vec![]),
+        };
+        let path = path.trim_matches('/');
+        let tree_sha = if path.is_empty() {
+            &head.tree_sha
+        } else {
+            match self.pack.find_entry(&head.tree_sha, path) {
+                Some(entry) if entry.is_dir => &entry.sha,
+                _ => return Ok(vec![]),
+            }
+        };
+        let key = if path.is_empty() { String::from(ROOT) } else { format!("/{}/", path) };
+        let mut children = self.build_children(&key, tree_sha, false, None)?;
+        if self.options.directories_only {
+            children.retain(|child| child.r#type == "directory");
+        }
+        Ok(children)
+    }
+
+    /**
+     * A directory node with everything below it. With a summary, only the nodes it keeps are made, and
+     * the counts of the node come from it, so they include what's left out.
+     */
+    fn build_tree_node(&self, path: String, name: String, tree_sha: &[u8], summary: Option<&TreeSummary>) -> Result<TreeNode, ProcessError> {
+        let mut children = self.build_children(&path, tree_sha, true, summary)?;
+        let (has_children, num_files, changed) = match summary.and_then(|summary| summary.dirs.get(&path)) {
+            Some(dir) => (dir.has_children, dir.num_files, dir.changed),
+            None => (
+                !children.is_empty(),
+                children.iter().map(|child| child.num_files).sum(),
+                children.iter().any(|child| child.changed.unwrap_or(child.num_changes > 0)),
+            ),
+        };
+        if self.options.directories_only {
+            children.retain(|child| child.r#type == "directory");
+        }
+        let num_changes = *self.num_changes.get(&path).unwrap_or(&0);
+        Ok(TreeNode {
+            name,
+            raw_name: None,
+            r#type: String::from("directory"),
+            blob_sha: None,
+            num_changes,
+            num_commits: self.num_commits_of(&path),
+            has_children,
+            bus_factor: self.bus_factor(&path),
+            top_authors: self.top_authors(&path),
+            children: children.into_iter().map(Box::new).collect(),
+            num_files,
+            changed: Some(changed),
+            normalized_heat: self.normalized_heat(num_changes, num_files),
+            truncated: false,
+            omitted_nodes: None,
+        })
+    }
+
+    /**
+     * The nodes for the entries of a tree. Without recurse, directories are left empty.
+     * With a summary, the entries that don't have a node or aren't kept are skipped.
+     */
+    fn build_children(&self, path: &str, tree_sha: &[u8], recurse: bool, summary: Option<&TreeSummary>) -> Result<Vec<TreeNode>, ProcessError> {
+        let tree = self.get_tree(tree_sha)?.map_or(&[][..], |tree| &tree[..]);
         let mut children = Vec::new();
 
-        for entry in tree {
+        for entry in tree {
+            if let Some(summary) = summary {
+                let key = if entry.is_dir { format!("{}{}/", path, entry.name) } else { format!("{}{}", path, entry.name) };
+                if (entry.is_dir && !summary.dirs.contains_key(&key))
+                    || summary.kept.as_ref().is_some_and(|(kept, _)| !kept.contains(&key)) {
+                    continue
+                }
+            }
+            if entry.is_dir {
+                let dir_path = format!("{}{}/", path, entry.name);
+                let mut node = if recurse {
+                    let node = self.build_tree_node(dir_path, entry.name.clone(), &entry.sha, summary)?;
+                    if self.prunes_empty_dirs() && !node.has_children {
+                        continue
+                    }
+                    node
+                } else {
+                    let num_changes = *self.num_changes.get(&dir_path).unwrap_or(&0);
+                    let (num_files, changed) = self.count_shown_files(&dir_path, &entry.sha)?;
+                    TreeNode {
+                        name: entry.name.clone(),
+                        raw_name: None,
+                        r#type: String::from("directory"),
+                        blob_sha: None,
+                        num_changes,
+                        num_commits: self.num_commits_of(&dir_path),
+                        has_children: self.get_tree(&entry.sha)?.is_some_and(|tree| !tree.is_empty()),
+                        bus_factor: self.bus_factor(&dir_path),
+                        top_authors: self.top_authors(&dir_path),
+                        children: vec![],
+                        num_files,
+                        changed: Some(changed),
+                        normalized_heat: self.normalized_heat(num_changes, num_files),
+                        truncated: false,
+                        omitted_nodes: None,
+                    }
+                };
+                node.raw_name = entry.raw_name.as_ref().map(|raw_name| ashex(raw_name));
+                children.push(node);
+            } else {
+                let file_path = format!("{}{}", path, entry.name);
+                if !self.shows_file(&file_path) {
+                    continue
+                }
+                let num_changes = *self.num_changes.get(&file_path).unwrap_or(&0);
+                children.push(TreeNode {
+                    name: entry.name.clone(),
+                    raw_name: entry.raw_name.as_ref().map(|raw_name| ashex(raw_name)),
+                    r#type: String::from("file"),
+                    blob_sha: if self.options.include_shas { Some(ashex(&entry.sha)) } else { None },
+                    num_changes,
+                    num_commits: self.num_commits_of(&file_path),
+                    has_children: false,
+                    bus_factor: None,
+                    top_authors: self.top_authors(&file_path),
+                    children: vec![],
+                    num_files: 1,
+                    changed: None,
+                    normalized_heat: None,
+                    truncated: false,
+                    omitted_nodes: None,
+                });
+            }
+        }
+
+        // Directories first, then files, each sorted by name
+        children.sort_by(|a, b| {
+            let a_is_file = a.r#type == "file";
+            let b_is_file = b.r#type == "file";
+            a_is_file.cmp(&b_is_file).then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(children)
+    }
+
+    /**
+     * Files that a counted commit deleted and that aren't in the head tree, with the newest commit that
+     * deleted them, ordered by path. A renamed file counts as deleted under its old path.
+     */
+    pub fn deleted_files(&self) -> Result<Vec<(String, Sha)>, ProcessError> {
+        let at_head: HashSet<String> = self.blob_keys()?.into_values().flatten().collect();
+        let mut files: Vec<(String, Sha)> = self.deleted.iter()
+            .filter(|(key, _)| !at_head.contains(*key))
+            .map(|(key, sha)| (self.output_path(key), sha.clone()))
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+
+    /**
+     * For every file of the head tree, how many counted commits there were since it was added and how many of
+     * those changed it, ordered by path. touch_commits / lifespan_commits is how often the file changes.
+     * A file that was there before the oldest counted commit (like in a root commit, or before since) has
+     * all counted commits as its lifespan. The commits since are the ones at or after the date of the one
+     * that added it, so for a branching history that includes commits of other branches.
+     */
+    pub fn file_lifespans(&self) -> Result<Vec<FileLifespan>, ProcessError> {
+        let mut files: Vec<FileLifespan> = self.blob_keys()?.into_values().flatten()
+            .filter(|key| !self.is_ignored(key))
+            .map(|key| {
+                let lifespan_commits = match self.first_added.get(&key) {
+                    Some(added) => self.commit_dates.iter().filter(|date| *date >= added).count() as u32,
+                    None => self.num_commits,
+                };
+                FileLifespan {
+                    path: self.output_path(&key),
+                    lifespan_commits,
+                    touch_commits: self.num_commits_of(&key),
+                }
+            })
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(files)
+    }
+
+    /**
+     * For every counted commit that changed something, newest first, the directories it changed files in, ordered by path.
+     * Needs the directory_matrix option, without it this is empty.
+     */
+    pub fn commit_directory_matrix(&self) -> Vec<(Sha, Vec<String>)> {
+        self.directory_matrix.iter()
+            .map(|(sha, dirs)| {
+                (sha.clone(), dirs.iter().map(|dir| self.output_path(dir)).collect())
+            })
+            .collect()
+    }
+
+    /**
+     * How few authors made more than half of the changes in a directory, so their knowledge of it
+     * would mostly be gone without them. 0 for directories without changes, None without the bus_factor option.
+     */
+    pub fn bus_factor(&self, path: &str) -> Option<u32> {
+        if !self.options.bus_factor {
+            return None;
+        }
+        let key = format!("{}{}", ROOT, path.trim_matches('/'));
+        let key = if key == ROOT { key } else { format!("{}/", key) };
+        let mut changes: Vec<u32> = match self.path_authors.get(&key) {
+            Some(authors) => authors.values().cloned().collect(),
+            None => return Some(0),
+        };
+        changes.sort_unstable_by(|a, b| b.cmp(a));
+
+        let total: u32 = changes.iter().sum();
+        let mut covered = 0;
+        let mut authors = 0;
+        for amount in changes {
+            if covered * 2 > total {
+                break;
+            }
+            covered += amount;
+            authors += 1;
+        }
+        Some(authors)
+    }
+
+    /**
+     * The authors (by email) with the most changes to a file ("src/main.rs") or directory ("src/"), most first,
+     * as many as the top_authors option allows. Empty without changes, None without the option.
+     */
+    pub fn top_authors(&self, path: &str) -> Option<Vec<AuthorChangeCount>> {
+        let limit = self.options.top_authors?;
+        let key = format!("{}{}", ROOT, path.trim_start_matches('/'));
+        let mut authors: Vec<AuthorChangeCount> = match self.path_authors.get(&key) {
+            Some(authors) => authors.iter().map(|(email, changes)| AuthorChangeCount { email: email.clone(), changes: *changes }).collect(),
+            None => vec![],
+        };
+        authors.sort_by(|a, b| b.changes.cmp(&a.changes).then_with(|| a.email.cmp(&b.email)));
+        authors.truncate(limit);
+        Some(authors)
+    }
+
+    fn write_top_authors_json<W: Write>(&self, out: &mut W, path: &str) -> io::Result<()> {
+        let authors = match self.top_authors(path) {
+            Some(authors) => authors,
+            None => return Ok(()),
+        };
+        out.write_all(br#","topAuthors":["#)?;
+        for (i, author) in authors.iter().enumerate() {
+            if i > 0 {
+                out.write_all(b",")?;
+            }
+            out.write_all(br#"{"email":"#)?;
+            write_json_string(out, &author.email)?;
+            write!(out, r#","changes":{}}}"#, author.changes)?;
+        }
+        out.write_all(b"]")
+    }
+
+    fn shows_file(&self, file_path: &str) -> bool {
+        !self.is_ignored(file_path) && *self.num_changes.get(file_path).unwrap_or(&0) >= self.options.min_changes
+    }
+
+    /**
+     * Whether directories without any files left in them are left out of the tree.
+     */
+    fn prunes_empty_dirs(&self) -> bool {
+        self.options.min_changes > 0 || !self.options.include_globs.is_empty()
+    }
+
+    /**
+     * The TreeSummary of the head tree, if pruned directories or max_output_nodes need one.
+     */
+    fn summarize(&self, tree_sha: &[u8]) -> Result<Option<TreeSummary>, ProcessError> {
+        if !self.prunes_empty_dirs() && self.options.max_output_nodes.is_none() {
+            return Ok(None);
+        }
+        let mut dirs = HashMap::new();
+        let mut ranking = self.options.max_output_nodes.map(|max| NodeRanking { max, best: BinaryHeap::new(), count: 0 });
+        let root = self.summarize_dir(ROOT, tree_sha, &mut dirs, &mut ranking)?;
+        dirs.insert(String::from(ROOT), root);
+        Ok(Some(TreeSummary {
+            dirs,
+            kept: ranking.and_then(|ranking| self.truncation(ranking)),
+        }))
+    }
+
+    /**
+     * Adds the directories below path that have a node to dirs and the nodes there are to the ranking,
+     * and returns the summary of path itself, from the bottom up.
+     */
+    fn summarize_dir(&self, path: &str, tree_sha: &[u8], dirs: &mut HashMap<String, DirSummary>, ranking: &mut Option<NodeRanking>) -> Result<DirSummary, ProcessError> {
+        let tree = self.get_tree(tree_sha)?.map_or(&[][..], |tree| &tree[..]);
+        let mut summary = DirSummary { num_files: 0, changed: false, has_children: false };
+        for entry in tree {
+            if entry.is_dir {
+                let key = format!("{}{}/", path, entry.name);
+                let dir = self.summarize_dir(&key, &entry.sha, dirs, ranking)?;
+                // With prunes_empty_dirs only the directories with shown files below them have a node
+                if self.prunes_empty_dirs() && dir.num_files == 0 {
+                    continue
+                }
+                summary.num_files += dir.num_files;
+                summary.changed |= dir.changed;
+                summary.has_children = true;
+                if let Some(ranking) = ranking {
+                    ranking.push(*self.num_changes.get(&key).unwrap_or(&0), key.clone());
+                }
+                dirs.insert(key, dir);
+            } else {
+                let key = format!("{}{}", path, entry.name);
+                if !self.shows_file(&key) {
+                    continue
+                }
+                let num_changes = *self.num_changes.get(&key).unwrap_or(&0);
+                summary.num_files += 1;
+                summary.changed |= num_changes > 0;
+                summary.has_children = true;
+                if let Some(ranking) = ranking.as_mut().filter(|_| !self.options.directories_only) {
+                    ranking.push(num_changes, key);
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /**
+     * The nodes (by key) a tree with more than max_output_nodes keeps, and how many it leaves out.
+     * The root doesn't count. Nodes are kept by most changes first, then by path, each together with its
+     * directories, until the next one doesn't fit anymore. None if the tree isn't truncated.
+     */
+    fn truncation(&self, ranking: NodeRanking) -> Option<(HashSet<String>, u32)> {
+        if ranking.count <= ranking.max {
+            return None;
+        }
+        let mut kept = HashSet::new();
+        // Ascending is best first
+        for (_, key) in ranking.best.into_sorted_vec() {
+            let missing: Vec<&str> = directories_of(&key).chain(std::iter::once(key.as_str()))
+                .filter(|node| !kept.contains(*node))
+                .collect();
+            if kept.len() + missing.len() > ranking.max {
+                break
+            }
+            kept.extend(missing.into_iter().map(String::from));
+        }
+        let omitted = (ranking.count - kept.len()) as u32;
+        Some((kept, omitted))
+    }
+
+    /**
+     * Writes the JSON of the tree build_tree returns, but node by node while going through the head tree,
+     * so only the directories on the current path are held in memory instead of the whole tree.
+     */
+    pub fn write_tree_json<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self.get_commit(&self.head).map_err(to_io_error)? {
+            Some(head) => {
+                let summary = self.summarize(&head.tree_sha).map_err(to_io_error)?;
+                self.write_node_json(out, ROOT, "", None, &head.tree_sha, summary.as_ref())?;
+                if let Some((_, omitted)) = summary.as_ref().and_then(|summary| summary.kept.as_ref()) {
+                    write!(out, r#","truncated":true,"omittedNodes":{}"#, omitted)?;
+                }
+                out.write_all(b"}")
+            }
+            None => {
+                out.write_all(br#"{"name":"","type":"directory","numChanges":0,"numCommits":0,"children":[],"numFiles":0,"changed":false"#)?;
+                self.write_heat_json(out, 0, 0)?;
+                out.write_all(b"}")
+            }
+        }
+    }
+
+    /**
+     * Writes a directory node but its closing brace, and returns its numFiles and changed.
+     * With a summary (see summarize) only the children it keeps are written, and the counts come from it.
+     */
+    fn write_node_json<W: Write>(&self, out: &mut W, path: &str, name: &str, raw_name: Option<&[u8]>, tree_sha: &[u8], summary: Option<&TreeSummary>) -> io::Result<(u32, bool)> {
+        let mut entries = self.shown_entries(path, tree_sha, summary).map_err(to_io_error)?;
+        let num_changes = *self.num_changes.get(path).unwrap_or(&0);
+        let mut num_files = entries.iter().filter(|entry| !entry.is_dir).count() as u32;
+        let mut changed = entries.iter()
+            .any(|entry| !entry.is_dir && *self.num_changes.get(&format!("{}{}", path, entry.name)).unwrap_or(&0) > 0);
+
+        out.write_all(br#"{"name":"#)?;
+        write_json_string(out, name)?;
+        if let Some(raw_name) = raw_name {
+            out.write_all(br#","rawName":"#)?;
+            write_json_string(out, &ashex(raw_name))?;
+        }
+        write!(out, r#","type":"directory","numChanges":{},"numCommits":{}"#, num_changes, self.num_commits_of(path))?;
+        if !entries.is_empty() {
+            out.write_all(br#","hasChildren":true"#)?;
+        }
+        if self.options.directories_only {
+            entries.retain(|entry| entry.is_dir);
+        }
+        if let Some((kept, _)) = summary.and_then(|summary| summary.kept.as_ref()) {
+            entries.retain(|entry| {
+                let key = if entry.is_dir { format!("{}{}/", path, entry.name) } else { format!("{}{}", path, entry.name) };
+                kept.contains(&key)
+            });
+        }
+        if let Some(bus_factor) = self.bus_factor(path) {
+            write!(out, r#","busFactor":{}"#, bus_factor)?;
+        }
+        self.write_top_authors_json(out, path)?;
+        out.write_all(br#","children":["#)?;
+
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                out.write_all(b",")?;
+            }
             if entry.is_dir {
-                children.push(Box::new(self.build_tree_node(format!("{}{}/", path, entry.name), entry.name.clone(), &entry.sha)));
+                let (dir_files, dir_changed) = self.write_node_json(out, &format!("{}{}/", path, entry.name), &entry.name, entry.raw_name.as_deref(), &entry.sha, summary)?;
+                num_files += dir_files;
+                changed |= dir_changed;
+                out.write_all(b"}")?;
+                continue
+            }
+            out.write_all(br#"{"name":"#)?;
+            write_json_string(out, &entry.name)?;
+            if let Some(raw_name) = &entry.raw_name {
+                out.write_all(br#","rawName":"#)?;
+                write_json_string(out, &ashex(raw_name))?;
+            }
+            out.write_all(br#","type":"file""#)?;
+            if self.options.include_shas {
+                write!(out, r#","blobSha":"{}""#, ashex(&entry.sha))?;
+            }
+            let file_path = format!("{}{}", path, entry.name);
+            write!(out, r#","numChanges":{},"numCommits":{}"#, self.num_changes.get(&file_path).unwrap_or(&0), self.num_commits_of(&file_path))?;
+            self.write_top_authors_json(out, &file_path)?;
+            out.write_all(br#","children":[],"numFiles":1}"#)?;
+        }
+        if let Some(dir) = summary.and_then(|summary| summary.dirs.get(path)) {
+            // Including what the truncation left out, like in build_tree
+            num_files = dir.num_files;
+            changed = dir.changed;
+        }
+        write!(out, r#"],"numFiles":{},"changed":{}"#, num_files, changed)?;
+        self.write_heat_json(out, num_changes, num_files)?;
+        Ok((num_files, changed))
+    }
+
+    fn write_heat_json<W: Write>(&self, out: &mut W, num_changes: u32, num_files: u32) -> io::Result<()> {
+        match self.normalized_heat(num_changes, num_files) {
+            // Debug keeps the ".0" of whole numbers, like serde_json
+            Some(heat) => write!(out, r#","normalizedHeat":{:?}"#, heat),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * The entries of a tree that build_children makes nodes for, in the same order, but not truncated yet.
+     * There's a summary whenever directories are pruned, which has the ones that are shown.
+     */
+    fn shown_entries(&self, path: &str, tree_sha: &[u8], summary: Option<&TreeSummary>) -> Result<Vec<&'a GitTreeEntry>, ProcessError> {
+        let tree = self.get_tree(tree_sha)?.map_or(&[][..], |tree| &tree[..]);
+        let mut entries = Vec::new();
+        for entry in tree {
+            let shown = if entry.is_dir {
+                match summary {
+                    Some(summary) => summary.dirs.contains_key(&format!("{}{}/", path, entry.name)),
+                    None => true,
+                }
             } else {
-                children.push(Box::new(TreeNode {
-                    name: entry.name.clone(),
-                    r#type: String::from("file"),
-                    numChanges: *self.num_changes.get(&format!("{}{}", path, entry.name)).unwrap_or(&0),
-                    children: vec![]
-                }));
+                self.shows_file(&format!("{}{}", path, entry.name))
+            };
+            if shown {
+                entries.push(entry);
             }
         }
+        entries.sort_by(|a, b| a.is_dir.cmp(&b.is_dir).reverse().then_with(|| a.name.cmp(&b.name)));
+        Ok(entries)
+    }
 
-        TreeNode {
-            name,
-            r#type: String::from("directory"),
-            numChanges: *self.num_changes.get(&path).unwrap_or(&0),
-            children
+    /**
+     * The numFiles and changed of a directory node, without building its children.
+     */
+    fn count_shown_files(&self, path: &str, tree_sha: &[u8]) -> Result<(u32, bool), ProcessError> {
+        let mut count = 0;
+        let mut changed = false;
+        self.walk_keys(tree_sha, path, &mut |key, entry| {
+            if !entry.is_dir && self.shows_file(key) {
+                count += 1;
+                changed |= *self.num_changes.get(key).unwrap_or(&0) > 0;
+            }
+        })?;
+        Ok((count, changed))
+    }
+
+    /**
+     * The normalized_heat of a directory node: its changes per file, 0 for a directory without files.
+     */
+    fn normalized_heat(&self, num_changes: u32, num_files: u32) -> Option<f64> {
+        if !self.options.normalized_heat {
+            return None;
         }
+        Some(if num_files == 0 { 0.0 } else { num_changes as f64 / num_files as f64 })
     }
 }
 
 
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // A zlib stream of data in one stored (uncompressed) deflate block
+    fn stored_zlib(data: &[u8]) -> Vec<u8> {
+        let mut out = vec![0x78, 0x01, 0x01];
+        let len = data.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(data);
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in data {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+        out
+    }
+
+    /**
+     * Builds a pack for tests, handing out the name of each object added. Objects are stored whole
+     * unless they are added with delta, and named with SHA-1 unless the pack is made with sha256.
+     */
+    #[derive(Default)]
+    pub(crate) struct TestPack {
+        objects: Vec<TestObject>,
+        date: i64, // Of the last commit made with commit, each one is a minute newer
+        sha256: bool,
+    }
+
+    struct TestObject {
+        sha: Sha,
+        obj_type: PackObjectType, // Of the object, not the delta
+        data: Vec<u8>, // The delta instead with a delta base
+        size: usize, // Of the object, which a delta on top of this one starts from
+        delta_base: Option<(Sha, bool)>, // The base of a delta, and whether it's an OFS_DELTA rather than a REF_DELTA
+    }
+
+    impl TestPack {
+        pub(crate) fn sha256() -> TestPack {
+            TestPack { sha256: true, ..Default::default() }
+        }
+
+        fn hash_algorithm(&self) -> HashAlgo {
+            if self.sha256 { HashAlgo::Sha256 } else { HashAlgo::Sha1 }
+        }
+
+        fn name(&self, obj_type: &PackObjectType, data: &[u8]) -> Sha {
+            let mut object = format!("{} {}\0", obj_type.git_name().unwrap(), data.len()).into_bytes();
+            object.extend_from_slice(data);
+            self.hash_algorithm().digest(&object)
+        }
+
+        fn add(&mut self, obj_type: PackObjectType, data: Vec<u8>) -> Sha {
+            let sha = self.name(&obj_type, &data);
+            self.objects.push(TestObject { sha: sha.clone(), obj_type, size: data.len(), data, delta_base: None });
+            sha
+        }
+
+        pub(crate) fn blob(&mut self, contents: &str) -> Sha {
+            self.blob_bytes(contents.as_bytes())
+        }
+
+        pub(crate) fn blob_bytes(&mut self, contents: &[u8]) -> Sha {
+            self.add(PackObjectType::ObjBlob, contents.to_vec())
+        }
+
+        // Entries by name, directories when their sha is one of a tree. Git orders them by name, with a slash after directories.
+        pub(crate) fn tree(&mut self, entries: &[(&str, &Sha, bool)]) -> Sha {
+            let entries: Vec<(&str, &[u8], &Sha)> = entries.iter()
+                .map(|&(name, sha, is_dir)| (if is_dir { "40000" } else { "100644" }, name.as_bytes(), sha))
+                .collect();
+            self.raw_tree(&entries)
+        }
+
+        // Entries as (mode, name, sha), for submodules ("160000"), symlinks ("120000") or names that aren't UTF-8
+        pub(crate) fn raw_tree(&mut self, entries: &[(&str, &[u8], &Sha)]) -> Sha {
+            let mut entries = entries.to_vec();
+            entries.sort_by_key(|&(mode, name, _)| {
+                let mut key = name.to_vec();
+                if mode == "40000" {
+                    key.push(b'/');
+                }
+                key
+            });
+            let mut data = Vec::new();
+            for (mode, name, sha) in entries {
+                data.extend_from_slice(mode.as_bytes());
+                data.push(b' ');
+                data.extend_from_slice(name);
+                data.push(0);
+                data.extend_from_slice(sha);
+            }
+            self.add(PackObjectType::ObjTree, data)
+        }
+
+        // The tree of files given as (path, contents), with the trees of their directories
+        pub(crate) fn files(&mut self, files: &[(&str, &str)]) -> Sha {
+            let mut entries: Vec<(String, Sha, bool)> = Vec::new();
+            let mut dirs: Vec<(&str, Vec<(&str, &str)>)> = Vec::new();
+            for &(path, contents) in files {
+                match path.split_once('/') {
+                    Some((dir, rest)) => match dirs.iter_mut().find(|(name, _)| *name == dir) {
+                        Some((_, below)) => below.push((rest, contents)),
+                        None => dirs.push((dir, vec![(rest, contents)])),
+                    },
+                    None => entries.push((path.to_owned(), self.blob(contents), false)),
+                }
+            }
+            for (dir, below) in dirs {
+                entries.push((dir.to_owned(), self.files(&below), true));
+            }
+            let entries: Vec<(&str, &Sha, bool)> = entries.iter().map(|(name, sha, is_dir)| (name.as_str(), sha, *is_dir)).collect();
+            self.tree(&entries)
+        }
+
+        pub(crate) fn commit(&mut self, tree: &Sha, parents: &[&Sha]) -> Sha {
+            self.date += 60;
+            self.commit_by(tree, parents, "test@example.com", 1_600_000_000 + self.date)
+        }
+
+        // A commit by email at date (unix seconds), as author and committer
+        pub(crate) fn commit_by(&mut self, tree: &Sha, parents: &[&Sha], email: &str, date: i64) -> Sha {
+            let mut data = format!("tree {}\n", ashex(tree));
+            for parent in parents {
+                data.push_str(&format!("parent {}\n", ashex(parent)));
+            }
+            let signature = format!("Test <{}> {} +0000", email, date);
+            data.push_str(&format!("author {}\ncommitter {}\n\nCommit {}\n", signature, signature, self.objects.len()));
+            self.add(PackObjectType::ObjCommit, data.into_bytes())
+        }
+
+        // An object with the type of base, stored as a delta inserting all of data against base, which has to be in the pack already
+        pub(crate) fn delta(&mut self, base: &Sha, data: &[u8], ofs: bool) -> Sha {
+            let base_object = self.objects.iter().find(|object| &object.sha == base).unwrap();
+            let obj_type = base_object.obj_type.clone();
+            let mut delta = Vec::new();
+            for mut size in [base_object.size, data.len()] {
+                while size >= 0x80 {
+                    delta.push(0x80 | (size & 0x7f) as u8);
+                    size >>= 7;
+                }
+                delta.push(size as u8);
+            }
+            for insert in data.chunks(0x7f) {
+                delta.push(insert.len() as u8);
+                delta.extend_from_slice(insert);
+            }
+            let sha = self.name(&obj_type, data);
+            self.objects.push(TestObject { sha: sha.clone(), obj_type, data: delta, size: data.len(), delta_base: Some((base.clone(), ofs)) });
+            sha
+        }
+
+        pub(crate) fn finish(&self) -> Vec<u8> {
+            let mut pack = b"PACK".to_vec();
+            pack.extend_from_slice(&2u32.to_be_bytes());
+            pack.extend_from_slice(&(self.objects.len() as u32).to_be_bytes());
+            let mut offsets = Vec::new();
+            for object in &self.objects {
+                offsets.push(pack.len());
+                match &object.delta_base {
+                    Some((base, true)) => {
+                        pack.extend(entry_header(PackObjectType::ObjOfsDelta as u8, object.data.len()));
+                        let base_offset = offsets[self.objects.iter().position(|other| &other.sha == base).unwrap()];
+                        // 7 bits per byte, most significant first, with one taken off all but the last group
+                        let mut distance = offsets.last().unwrap() - base_offset;
+                        let mut bytes = vec![(distance & 0x7f) as u8];
+                        distance >>= 7;
+                        while distance != 0 {
+                            distance -= 1;
+                            bytes.insert(0, 0x80 | (distance & 0x7f) as u8);
+                            distance >>= 7;
+                        }
+                        pack.extend(bytes);
+                    }
+                    Some((base, false)) => {
+                        pack.extend(entry_header(PackObjectType::ObjRefDelta as u8, object.data.len()));
+                        pack.extend_from_slice(base);
+                    }
+                    None => pack.extend(entry_header(object.obj_type.clone() as u8, object.data.len())),
+                }
+                pack.extend(stored_zlib(&object.data));
+            }
+            let checksum = self.hash_algorithm().digest(&pack);
+            pack.extend(checksum);
+            pack
+        }
+    }
+
+    // The type and size header of an entry, laid out like git does
+    fn entry_header(type_bits: u8, size: usize) -> Vec<u8> {
+        let mut header = vec![(type_bits << 4) | (size & 0b1111) as u8];
+        let mut rest = size >> 4;
+        while rest != 0 {
+            *header.last_mut().unwrap() |= 1 << 7;
+            header.push((rest & 0b111_1111) as u8);
+            rest >>= 7;
+        }
+        header
+    }
+
+    #[test]
+    fn read_entry_header() {
+        let types = [
+            (1, PackObjectType::ObjCommit),
+            (2, PackObjectType::ObjTree),
+            (3, PackObjectType::ObjBlob),
+            (4, PackObjectType::ObjTag),
+        ];
+        // 15 and 16 are on both sides of the first continuation byte, 2047 and 2048 of the second
+        for &size in &[0, 1, 15, 16, 17, 2047, 2048, 40000] {
+            let header = entry_header(3, size);
+            assert_eq!(header.len(), if size < 16 { 1 } else if size < 2048 { 2 } else { 3 });
+            for (type_bits, obj_type) in types.iter() {
+                let data = vec![b'x'; size];
+                let mut entry = entry_header(*type_bits, size);
+                entry.extend(stored_zlib(&data));
+                let decoded = read_entry(&entry, 0, 20).unwrap();
+                assert!(decoded.obj_type == *obj_type);
+                assert!(decoded.base.is_none());
+                assert_eq!(decoded.data, data);
+                assert_eq!(decoded.len, entry.len());
+            }
+        }
+    }
+
+    #[test]
+    fn read_entry_delta_header() {
+        let delta = vec![b'd'; 20];
+        let sha = [7u8; 32];
+
+        let mut entry = entry_header(7, delta.len());
+        entry.extend_from_slice(&sha);
+        entry.extend(stored_zlib(&delta));
+        let decoded = read_entry(&entry, 0, 32).unwrap();
+        assert!(decoded.obj_type == PackObjectType::ObjRefDelta);
+        assert!(matches!(decoded.base, Some(DeltaBase::Sha(base)) if base == &sha[..]));
+        assert_eq!(decoded.data, delta);
+
+        // A distance of 200 takes two bytes: (0 + 1) * 128 + 72
+        let mut entry = entry_header(6, delta.len());
+        entry.extend_from_slice(&[0x80, 72]);
+        entry.extend(stored_zlib(&delta));
+        let decoded = read_entry(&entry, 1000, 20).unwrap();
+        assert!(decoded.obj_type == PackObjectType::ObjOfsDelta);
+        assert!(matches!(decoded.base, Some(DeltaBase::Offset(800))));
+        assert_eq!(decoded.data, delta);
+    }
+
+    #[test]
+    fn read_entry_bad_header() {
+        for &type_bits in &[0, 5] {
+            let mut entry = entry_header(type_bits, 3);
+            entry.extend(stored_zlib(b"abc"));
+            assert_eq!(read_entry(&entry, 12, 20).err(), Some(ParseError::UnknownObjectType { offset: 12, obj_type: type_bits }));
+        }
+
+        // The header says there are more size bytes, but the data ends
+        assert_eq!(read_entry(&[0x80 | 0x30], 12, 20).err(), Some(ParseError::TruncatedEntry { offset: 12 }));
+
+        let mut entry = entry_header(3, 16);
+        entry.extend(stored_zlib(&[b'x'; 15]));
+        assert_eq!(read_entry(&entry, 12, 20).err(), Some(ParseError::SizeMismatch { offset: 12 }));
+    }
+
+    #[test]
+    fn object_count() {
+        let mut pack = TestPack::default();
+        pack.blob("a");
+        pack.blob("b");
+        let pack = pack.finish();
+
+        // The same two objects, with the header declaring another count and the checksum redone
+        let declaring = |declared: u32| {
+            let mut data = pack[..pack.len() - 20].to_vec();
+            data[8..12].copy_from_slice(&declared.to_be_bytes());
+            let checksum = HashAlgo::Sha1.digest(&data);
+            data.extend(checksum);
+            data
+        };
+        let streamed = |data: &[u8]| {
+            let mut parser = PackStreamParser::new(ParseOptions::default(), HashAlgo::Sha1);
+            parser.push(data)?;
+            parser.finish()
+        };
+
+        for data in [declaring(2), pack.clone()] {
+            assert_eq!(parse_pack(&data).unwrap().stats.num_objects, 2);
+            assert_eq!(streamed(&data).unwrap().stats.num_objects, 2);
+        }
+        assert_eq!(parse_pack(&declaring(3)).err(), Some(ParseError::MissingObjects { declared: 3, found: 2 }));
+        assert_eq!(streamed(&declaring(3)).err(), Some(ParseError::MissingObjects { declared: 3, found: 2 }));
+        assert_eq!(parse_pack(&declaring(1)).err(), Some(ParseError::TooManyObjects { declared: 1, found: 2 }));
+        assert_eq!(streamed(&declaring(1)).err(), Some(ParseError::TooManyObjects { declared: 1, found: 2 }));
+    }
+
+    #[test]
+    fn watch_paths_prune() {
+        // Every commit changes small/a and a file deep down in big/, so only big/ needs pruning
+        let mut pack = TestPack::default();
+        let mut parent: Option<Sha> = None;
+        for i in 0..4 {
+            let a = pack.blob(&format!("a{}", i));
+            let b = pack.blob("b");
+            let deep = pack.blob(&format!("deep{}", i));
+            let inner = pack.tree(&[("deep", &deep, false)]);
+            let big = pack.tree(&[("inner", &inner, true), ("b", &b, false)]);
+            let small = pack.tree(&[("a", &a, false), ("b", &b, false)]);
+            let root = pack.tree(&[("big", &big, true), ("small", &small, true)]);
+            let parents: Vec<&Sha> = parent.iter().collect();
+            parent = Some(pack.commit(&root, &parents));
+        }
+        let repo = parse_pack(&pack.finish()).unwrap();
+        let head = parent.unwrap();
+
+        let mut counter = ChangeCounter::new(&repo, &head, ChangeCounterOptions::default());
+        counter.walk().unwrap();
+        // The root, small/, big/ and big/inner/ of each of the 3 commits with a parent
+        assert_eq!(counter.trees_diffed.load(Ordering::Relaxed), 12);
+        assert_eq!(counter.changes_for("big/inner/deep"), Some(3));
+
+        let mut options = ChangeCounterOptions::default();
+        options.watch_paths = vec!["small/a".to_owned()];
+        let mut watching = ChangeCounter::new(&repo, &head, options);
+        watching.walk().unwrap();
+        // Only the root and small/, big/ is never gone into
+        assert_eq!(watching.trees_diffed.load(Ordering::Relaxed), 6);
+        assert_eq!(watching.watched_changes()["small/a"], 3);
+        assert_eq!(watching.changes_for("big/inner/deep"), None);
+        assert_eq!(watching.changes_for("big"), None);
+    }
+
+    #[test]
+    fn commit_info_hex() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("README.md", "hello")]);
+        let root = pack.commit(&tree, &[]);
+        let child = pack.commit(&tree, &[&root]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let json = serde_json::to_value(CommitInfo::from((&child, repo.commit(&child).unwrap()))).unwrap();
+        assert_eq!(json["sha"], ashex(&child).as_str());
+        assert_eq!(json["tree"], ashex(&tree).as_str());
+        assert_eq!(json["parents"], serde_json::Value::Array(vec![serde_json::Value::String(ashex(&root))]));
+        assert_eq!(json["sha"].as_str().unwrap().len(), 40);
+    }
+
+    // Commits at 1000, 2000, ... changing f each time, as (sha of the head, pack)
+    fn dated_history(num_commits: i64) -> (Sha, ParsePackResult) {
+        let mut pack = TestPack::default();
+        let mut parent: Option<Sha> = None;
+        for i in 1..=num_commits {
+            let tree = pack.files(&[("f", &format!("version {}", i)), ("same", "same")]);
+            let parents: Vec<&Sha> = parent.iter().collect();
+            parent = Some(pack.commit_by(&tree, &parents, "test@example.com", i * 1000));
+        }
+        (parent.unwrap(), parse_pack(&pack.finish()).unwrap())
+    }
+
+    #[test]
+    fn date_window() {
+        let (head, repo) = dated_history(5);
+        let mut options = ChangeCounterOptions::default();
+        // Only the three commits in the middle, the head is newer than until
+        options.since = Some(2000);
+        options.until = Some(4000);
+        let mut counter = ChangeCounter::new(&repo, &head, options);
+        counter.walk().unwrap();
+        assert_eq!(counter.num_commits(), 3);
+        assert_eq!(counter.changes_for("f"), Some(3));
+        assert_eq!(counter.changes_for("same"), None);
+    }
+
+    #[test]
+    fn reindented_lines() {
+        let mut pack = TestPack::default();
+        let before = pack.files(&[("f", "fn main() {\nprintln!();\n}\n")]);
+        let after = pack.files(&[("f", "fn main() {\n    println!();\n}\n")]);
+        let root = pack.commit(&before, &[]);
+        let head = pack.commit(&after, &[&root]);
+        let options = ParseOptions { retain_blobs: true, ..Default::default() };
+        let repo = parse_pack_with_options(&pack.finish(), &options).unwrap();
+
+        let churn = |ignore_whitespace| {
+            let mut options = ChangeCounterOptions::default();
+            options.metric = ChangeMetric::LineChurn { ignore_whitespace };
+            let mut counter = ChangeCounter::new(&repo, &head, options);
+            counter.walk().unwrap();
+            counter.changes_for("f").unwrap_or(0)
+        };
+        assert_eq!(churn(false), 2);
+        assert_eq!(churn(true), 0);
+    }
+
+    fn child<'t>(node: &'t TreeNode, name: &str) -> Option<&'t TreeNode> {
+        node.children.iter().find(|child| child.name == name).map(|child| &**child)
+    }
+
+    // A root commit and one that changes src/main.rs, with src/lib.rs and docs/readme never changed
+    fn one_change() -> (Sha, ParsePackResult) {
+        let mut pack = TestPack::default();
+        let root = pack.files(&[("src/main.rs", "a"), ("src/lib.rs", "lib"), ("docs/readme", "readme")]);
+        let root = pack.commit(&root, &[]);
+        let tree = pack.files(&[("src/main.rs", "b"), ("src/lib.rs", "lib"), ("docs/readme", "readme")]);
+        let head = pack.commit(&tree, &[&root]);
+        (head, parse_pack(&pack.finish()).unwrap())
+    }
+
+    #[test]
+    fn min_changes() {
+        let (head, repo) = one_change();
+        let mut options = ChangeCounterOptions::default();
+        options.min_changes = 1;
+        let tree = ChangeCounter::process(&repo, &head, options).unwrap();
+        assert_eq!(tree.children.len(), 1);
+        let src = child(&tree, "src").unwrap();
+        assert_eq!(src.children.len(), 1);
+        assert_eq!(child(src, "main.rs").unwrap().num_changes, 1);
+
+        let tree = ChangeCounter::process(&repo, &head, ChangeCounterOptions::default()).unwrap();
+        assert_eq!(tree.children.len(), 2);
+        assert_eq!(child(&tree, "src").unwrap().children.len(), 2);
+    }
+
+    // Redoes the SHA-1 checksum at the end of a pack after its contents were changed
+    fn rehash(pack: &mut Vec<u8>) {
+        pack.truncate(pack.len() - 20);
+        let checksum = HashAlgo::Sha1.digest(pack);
+        pack.extend(checksum);
+    }
+
+    #[test]
+    fn corrupt_deflate_stream() {
+        let mut pack = TestPack::default();
+        pack.blob("first");
+        pack.blob("second");
+        let mut data = pack.finish();
+        // The second entry starts after the 1 byte header and 16 bytes of zlib of the first one
+        let offset = 12 + 1 + 16;
+        // A deflate block of the reserved type 3 instead of a stored one
+        assert_eq!(data[offset + 3], 0x01);
+        data[offset + 3] = 0x07;
+        rehash(&mut data);
+        assert_eq!(parse_pack(&data).err(), Some(ParseError::ZlibError { offset }));
+    }
+
+    #[test]
+    fn recently_changed() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("a", "a1"), ("b", "b1"), ("c", "c1")]);
+        let first = pack.commit_by(&tree, &[], "test@example.com", 1000);
+        let tree = pack.files(&[("a", "a2"), ("b", "b1"), ("c", "c2")]);
+        let second = pack.commit_by(&tree, &[&first], "test@example.com", 2000);
+        let tree = pack.files(&[("a", "a2"), ("b", "b2"), ("c", "c2")]);
+        let third = pack.commit_by(&tree, &[&second], "test@example.com", 3000);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let mut counter = ChangeCounter::new(&repo, &third, ChangeCounterOptions::default());
+        counter.walk().unwrap();
+        // b was touched by the newest commit, a and c by the same one before, so they go by path
+        assert_eq!(counter.recently_changed(10), vec![
+            ("b".to_owned(), third.clone(), 3000),
+            ("a".to_owned(), second.clone(), 2000),
+            ("c".to_owned(), second, 2000),
+        ]);
+        assert_eq!(counter.recently_changed(1), vec![("b".to_owned(), third, 3000)]);
+    }
+
+    #[test]
+    fn child_order() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("b.txt", "b"), ("zdir/f", "f"), ("a.txt", "a"), ("adir/f", "f"), ("Z.txt", "z")]);
+        let head = pack.commit(&tree, &[]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        // Directories first, then files, each by name
+        let tree = ChangeCounter::process(&repo, &head, ChangeCounterOptions::default()).unwrap();
+        let names: Vec<&str> = tree.children.iter().map(|child| child.name.as_str()).collect();
+        assert_eq!(names, ["adir", "zdir", "Z.txt", "a.txt", "b.txt"]);
+    }
+
+    #[test]
+    fn empty_commits() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("f", "1")]);
+        let root = pack.commit_by(&tree, &[], "alice@example.com", 1000);
+        let tree = pack.files(&[("f", "2")]);
+        let change = pack.commit_by(&tree, &[&root], "alice@example.com", 2000);
+        // Like git commit --allow-empty
+        let empty = pack.commit_by(&tree, &[&change], "bob@example.com", 3000);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let count = |ignore_empty_commits| {
+            let mut options = ChangeCounterOptions::default();
+            options.ignore_empty_commits = ignore_empty_commits;
+            let mut counter = ChangeCounter::new(&repo, &empty, options);
+            counter.walk().unwrap();
+            let bob = counter.contributors().iter().find(|contributor| contributor.email == "bob@example.com").map_or(0, |bob| bob.commits);
+            (counter.changes_for("f"), counter.num_commits(), bob)
+        };
+        assert_eq!(count(false), (Some(1), 3, 1));
+        assert_eq!(count(true), (Some(1), 2, 0));
+    }
+
+    #[test]
+    fn raw_names() {
+        let mut pack = TestPack::default();
+        let blob = pack.blob("contents");
+        let tree = pack.raw_tree(&[("100644", b"name\xff", &blob), ("100644", b"name\xfe", &blob), ("100644", b"plain", &blob)]);
+        let head = pack.commit(&tree, &[]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let tree = ChangeCounter::process(&repo, &head, ChangeCounterOptions::default()).unwrap();
+        let nodes: Vec<(&str, Option<&str>)> = tree.children.iter().map(|child| (child.name.as_str(), child.raw_name.as_deref())).collect();
+        // Both names turn into the same string, only the raw names tell them apart
+        assert_eq!(nodes, [("name\u{fffd}", Some("6e616d65fe")), ("name\u{fffd}", Some("6e616d65ff")), ("plain", None)]);
+    }
+
+    #[test]
+    fn include_shas() {
+        let mut pack = TestPack::default();
+        let blob = pack.blob("fn main() {}");
+        let src = pack.tree(&[("main.rs", &blob, false)]);
+        let tree = pack.tree(&[("src", &src, true)]);
+        let head = pack.commit(&tree, &[]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let process = |include_shas| {
+            let options = ChangeCounterOptions { include_shas, ..Default::default() };
+            ChangeCounter::process(&repo, &head, options).unwrap()
+        };
+        let tree = process(true);
+        let src = child(&tree, "src").unwrap();
+        assert_eq!(src.blob_sha, None);
+        assert_eq!(child(src, "main.rs").unwrap().blob_sha.as_deref(), Some(ashex(&blob).as_str()));
+        assert_eq!(child(src, "main.rs").unwrap().blob_sha.as_ref().unwrap().len(), 40);
+
+        let tree = process(false);
+        assert_eq!(child(child(&tree, "src").unwrap(), "main.rs").unwrap().blob_sha, None);
+    }
+
+    #[test]
+    fn delta_free_pack() {
+        let mut pack = TestPack::default();
+        let readme = pack.blob("readme");
+        let main = pack.blob("fn main() {}");
+        let src = pack.tree(&[("main.rs", &main, false)]);
+        let tree = pack.tree(&[("README", &readme, false), ("src", &src, true)]);
+        let root = pack.commit(&tree, &[]);
+        let main = pack.blob("fn main() { run() }");
+        let src = pack.tree(&[("main.rs", &main, false)]);
+        let tree = pack.tree(&[("README", &readme, false), ("src", &src, true)]);
+        let head = pack.commit(&tree, &[&root]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        assert_eq!(repo.warnings, []);
+        assert_eq!(repo.stats.num_objects, 9);
+        assert_eq!(repo.stats.num_deltas, 0);
+        assert_eq!(repo.stats.max_delta_depth, 0);
+        assert!(!repo.stats.is_thin);
+        let tree = ChangeCounter::process(&repo, &head, ChangeCounterOptions::default()).unwrap();
+        assert_eq!(child(child(&tree, "src").unwrap(), "main.rs").unwrap().num_changes, 1);
+    }
+
+    #[test]
+    fn too_many_tree_entries() {
+        // Entries only need a mode, a name and a sha to count, so a corrupt tree packs a lot of them
+        let entries = |count: usize| -> Vec<u8> { (0..count).flat_map(|_| b"100644 f\0".iter().copied().chain([0; 20])).collect() };
+        assert_eq!(parse_tree(&entries(1000), 20, 1000).unwrap().len(), 1000);
+        assert!(matches!(parse_tree(&entries(1001), 20, 1000), Err(ParseError::TooManyTreeEntries { limit: 1000 })));
+        assert!(matches!(parse_tree(&entries(200_000), 20, 1000), Err(ParseError::TooManyTreeEntries { limit: 1000 })));
+
+        let mut pack = TestPack::default();
+        let tree = pack.add(PackObjectType::ObjTree, entries(2000));
+        pack.commit(&tree, &[]);
+        let data = pack.finish();
+        let options = ParseOptions { max_tree_entries: 1000, ..Default::default() };
+        assert!(matches!(parse_pack_with_options(&data, &options), Err(ParseError::TooManyTreeEntries { limit: 1000 })));
+        assert_eq!(parse_pack(&data).unwrap().trees[&tree].len(), 2000);
+    }
+
+    #[test]
+    fn lenient_missing_tree() {
+        let mut pack = TestPack::default();
+        let readme = pack.blob("readme");
+        let docs = pack.tree(&[("readme", &readme, false)]);
+        let main = pack.blob("a");
+        let src = pack.tree(&[("main.rs", &main, false)]);
+        let tree = pack.tree(&[("docs", &docs, true), ("src", &src, true)]);
+        let root = pack.commit(&tree, &[]);
+        let main = pack.blob("b");
+        let src = pack.tree(&[("main.rs", &main, false)]);
+        let tree = pack.tree(&[("docs", &docs, true), ("src", &src, true)]);
+        let head = pack.commit(&tree, &[&root]);
+        // A partial pack, as if docs had been left out
+        pack.objects.retain(|object| object.sha != docs);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let strict = ChangeCounter::process(&repo, &head, ChangeCounterOptions::default());
+        assert!(matches!(strict, Err(ProcessError::MissingTree { sha }) if sha == ashex(&docs)));
+
+        let options = ChangeCounterOptions { lenient: true, ..Default::default() };
+        let tree = ChangeCounter::process(&repo, &head, options).unwrap();
+        assert_eq!(child(child(&tree, "src").unwrap(), "main.rs").unwrap().num_changes, 1);
+        // The entry is still there, there is just nothing known below it
+        let docs = child(&tree, "docs").unwrap();
+        assert!(docs.children.is_empty());
+        assert_eq!(docs.num_changes, 0);
+    }
+
+    #[test]
+    fn top_level_file() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("README.md", "hello"), ("src/lib.rs", "lib")]);
+        let root = pack.commit(&tree, &[]);
+        let tree = pack.files(&[("README.md", "hello world"), ("src/lib.rs", "lib")]);
+        let head = pack.commit(&tree, &[&root]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let mut counter = ChangeCounter::new(&repo, &head, ChangeCounterOptions::default());
+        counter.walk().unwrap();
+        assert_eq!(counter.changes_for("/README.md"), Some(1));
+        assert_eq!(counter.changes_for("README.md"), Some(1));
+
+        let json = serde_json::to_value(counter.build_tree().unwrap()).unwrap();
+        let readme = &json["children"][1];
+        assert_eq!(readme["name"], "README.md");
+        assert_eq!(readme["numChanges"], 1);
+        assert_eq!(json["numChanges"], 1);
+    }
+
+    #[cfg(feature = "myers")]
+    #[test]
+    fn line_diff_counts() {
+        let mut pack = TestPack::default();
+        let old = pack.files(&[("delete", "a\nb\nc\n"), ("insert", "a\nb\nc\n"), ("move", "a\nb\nc\nd\n"), ("replace", "a\nb\nc\nd\n")]);
+        let new = pack.files(&[("delete", "a\nc\n"), ("insert", "a\nb\nnew\nc\n"), ("move", "b\nc\nd\na\n"), ("replace", "a\nB\nC\nd\n")]);
+        let options = ParseOptions { retain_blobs: true, ..Default::default() };
+        let repo = parse_pack_with_options(&pack.finish(), &options).unwrap();
+
+        let counts: Vec<(String, Option<u32>, Option<u32>)> = repo.diff_paths(Some(&old), &new).unwrap().into_iter()
+            .map(|change| (change.path, change.lines_added, change.lines_removed))
+            .collect();
+        // What `git diff --numstat` shows, a moved line is removed in one place and added in another
+        let expected = [("delete", 0, 1), ("insert", 1, 0), ("move", 1, 1), ("replace", 2, 2)];
+        assert_eq!(counts, expected.iter().map(|&(path, added, removed)| (path.to_owned(), Some(added), Some(removed))).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pack_trailer() {
+        let build = |mut pack: TestPack| {
+            let blob = pack.blob("contents");
+            let tree = pack.tree(&[("file", &blob, false)]);
+            let head = pack.commit(&tree, &[]);
+            (head, pack.finish())
+        };
+        let (sha1_head, sha1) = build(TestPack::default());
+        let (sha256_head, sha256) = build(TestPack::sha256());
+
+        for (head, data, algo) in [(&sha1_head, &sha1, HashAlgo::Sha1), (&sha256_head, &sha256, HashAlgo::Sha256)] {
+            let repo = parse_pack(data).unwrap();
+            assert_eq!(repo.hash_algorithm, algo);
+            assert_eq!(repo.stats.num_objects, 3);
+            assert_eq!(head.len(), algo.size());
+            assert!(repo.commit(head).is_some());
+
+            let mut corrupt = data.clone();
+            *corrupt.last_mut().unwrap() ^= 1;
+            assert_eq!(parse_pack(&corrupt).err(), Some(ParseError::ChecksumMismatch));
+        }
+
+        let options = ParseOptions::default();
+        assert_eq!(parse_packs(&[&sha1, &sha256], &options).err(), Some(ParseError::HashAlgorithmMismatch { pack: 1 }));
+        assert_eq!(parse_packs(&[&sha256, &sha1], &options).err(), Some(ParseError::HashAlgorithmMismatch { pack: 1 }));
+    }
+
+    #[test]
+    fn duplicate_object() {
+        let mut pack = TestPack::default();
+        let blob = pack.blob("x");
+        pack.blob("x");
+        pack.blob("y");
+        let data = pack.finish();
+
+        // The second copy starts after the header, the entry header and the zlib stream of the first
+        let duplicate = ParseWarning::DuplicateObject { offset: 12 + 1 + 12, sha: ashex(&blob) };
+        assert_eq!(parse_pack(&data).unwrap().warnings, [duplicate]);
+        let options = ParseOptions { strict: true, ..Default::default() };
+        assert!(matches!(parse_pack_with_options(&data, &options), Err(ParseError::Warning(ParseWarning::DuplicateObject { .. }))));
+    }
+
+    #[test]
+    fn resume_walk() {
+        let (head, repo) = dated_history(6);
+        let mut whole = ChangeCounter::new(&repo, &head, ChangeCounterOptions::default());
+        whole.walk().unwrap();
+
+        let mut counter = ChangeCounter::new(&repo, &head, ChangeCounterOptions::default());
+        assert!(!counter.walk_steps(Some(2)).unwrap());
+        let state = counter.save_state();
+        drop(counter);
+        let mut resumed = ChangeCounter::load_state(&repo, &head, ChangeCounterOptions::default(), state);
+        assert!(!resumed.walk_steps(Some(2)).unwrap());
+        assert!(resumed.walk_steps(None).unwrap());
+
+        assert_eq!(resumed.num_commits(), 6);
+        assert_eq!(resumed.changes_for("f"), whole.changes_for("f"));
+        // Every commit but the root one, which adds f
+        assert_eq!(resumed.changes_for("f"), Some(5));
+        assert_eq!(serde_json::to_string(&resumed.build_tree().unwrap()).unwrap(), serde_json::to_string(&whole.build_tree().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn submodule_bump() {
+        let mut pack = TestPack::default();
+        let readme = pack.blob("readme");
+        let (old, new) = (vec![1; 20], vec![2; 20]);
+        let vendor = pack.raw_tree(&[("160000", b"lib", &old)]);
+        let tree = pack.raw_tree(&[("100644", b"README", &readme), ("40000", b"vendor", &vendor)]);
+        let root = pack.commit(&tree, &[]);
+        let vendor = pack.raw_tree(&[("160000", b"lib", &new)]);
+        let tree = pack.raw_tree(&[("100644", b"README", &readme), ("40000", b"vendor", &vendor)]);
+        let head = pack.commit(&tree, &[&root]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let changes = |ignore_submodules| {
+            let mut counter = ChangeCounter::new(&repo, &head, ChangeCounterOptions { ignore_submodules, ..Default::default() });
+            counter.walk().unwrap();
+            (counter.changes_for("vendor/lib"), counter.changes_for("vendor"))
+        };
+        // The commits the submodule points to aren't in the pack, the change of the pointer is all there is
+        assert_eq!(changes(false), (Some(1), Some(1)));
+        assert_eq!(changes(true), (None, None));
+    }
+
+    #[test]
+    fn activity_buckets() {
+        const DAY: i64 = 24 * 60 * 60;
+        const MONDAY: i64 = 4 * DAY; // 1970-01-05
+        let buckets = |dates: &[i64]| {
+            let mut pack = TestPack::default();
+            let mut parent: Option<Sha> = None;
+            for (i, &date) in dates.iter().enumerate() {
+                let tree = pack.files(&[("f", &format!("version {}", i))]);
+                let parents: Vec<&Sha> = parent.iter().collect();
+                parent = Some(pack.commit_by(&tree, &parents, "test@example.com", date));
+            }
+            let head = parent.unwrap();
+            let repo = parse_pack(&pack.finish()).unwrap();
+            let mut counter = ChangeCounter::new(&repo, &head, ChangeCounterOptions::default());
+            counter.walk().unwrap();
+            (counter.activity_buckets(BucketSize::Week), counter.activity_buckets(BucketSize::Day))
+        };
+
+        // Three weeks with nothing in the middle one
+        let (weeks, days) = buckets(&[MONDAY + 60, MONDAY + 6 * DAY, MONDAY + 15 * DAY]);
+        assert_eq!(weeks, [(MONDAY, 2), (MONDAY + 7 * DAY, 0), (MONDAY + 14 * DAY, 1)]);
+        assert_eq!(days.len(), 16);
+        assert_eq!(days.iter().map(|&(_, commits)| commits).sum::<u32>(), 3);
+
+        // Commits too far apart to fill in the buckets between them
+        let (weeks, _) = buckets(&[-(1 << 60), MONDAY, 1 << 60]);
+        assert_eq!(weeks.len(), 3);
+        assert_eq!(weeks[1], (MONDAY, 1));
+        let (weeks, days) = buckets(&[i64::MIN, i64::MAX]);
+        assert_eq!(weeks.len(), 1);
+        assert_eq!(days.len(), 1);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_walk() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("a", "0"), ("dir/b", "0")]);
+        let mut head = pack.commit(&tree, &[]);
+        for i in 1..20 {
+            let version = i.to_string();
+            let tree = pack.files(&[("a", &version), ("dir/b", if i % 3 == 0 { "0" } else { &version }), ("dir/sub/c", &(i / 4).to_string())]);
+            let author = if i % 2 == 0 { "alice@example.com" } else { "bob@example.com" };
+            head = pack.commit_by(&tree, &[&head], author, 1000 * i);
+        }
+        // A side branch merged back in
+        let tree = pack.files(&[("a", "side"), ("side", "1")]);
+        let side = pack.commit_by(&tree, &[&head], "carol@example.com", 30_000);
+        let tree = pack.files(&[("a", "main")]);
+        let main = pack.commit_by(&tree, &[&head], "alice@example.com", 31_000);
+        let tree = pack.files(&[("a", "merged"), ("side", "1")]);
+        let head = pack.commit_by(&tree, &[&main, &side], "alice@example.com", 32_000);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let options = || ChangeCounterOptions { top_authors: Some(2), ..Default::default() };
+        let mut sequential = ChangeCounter::new(&repo, &head, options());
+        sequential.walk().unwrap();
+        let mut parallel = ChangeCounter::new(&repo, &head, options());
+        parallel.walk_parallel().unwrap();
+
+        assert_eq!(parallel.num_commits(), sequential.num_commits());
+        assert_eq!(parallel.num_commits(), 23);
+        assert_eq!(serde_json::to_string(&parallel.contributors()).unwrap(), serde_json::to_string(&sequential.contributors()).unwrap());
+        assert_eq!(serde_json::to_string(&parallel.build_tree().unwrap()).unwrap(), serde_json::to_string(&sequential.build_tree().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn verify_integrity() {
+        let mut pack = TestPack::default();
+        let a = pack.blob("a");
+        let b = pack.blob("b");
+        let data = pack.finish();
+        // An index with the wrong sha for the second object, which starts at 25, after the header and the first one
+        let wrong = vec![0xab; 20];
+        let index = PackIndex { entries: vec![(a, 12), (wrong.clone(), 25)] };
+
+        let mut options = ParseOptions { index: Some(index), verify_integrity: true, ..Default::default() };
+        let mismatch = ParseWarning::ShaMismatch { offset: 25, expected: ashex(&wrong), got: ashex(&b) };
+        assert_eq!(parse_pack_with_options(&data, &options).unwrap().warnings, [mismatch]);
+
+        options.strict = true;
+        assert!(matches!(parse_pack_with_options(&data, &options), Err(ParseError::Warning(ParseWarning::ShaMismatch { .. }))));
+        options.strict = false;
+        options.verify_integrity = false;
+        assert_eq!(parse_pack_with_options(&data, &options).unwrap().warnings, []);
+    }
+
+    #[test]
+    fn tree_json_matches_serde() {
+        let mut pack = TestPack::default();
+        let mut head: Option<Sha> = None;
+        for i in 0..6 {
+            let version = i.to_string();
+            let docs = pack.blob(if i < 2 { "docs" } else { "more docs" });
+            let odd = pack.blob(&version);
+            let docs = pack.raw_tree(&[("100644", b"name \"quoted\" \\ \t\x01 \xc3\xa9.md", &docs), ("100644", b"bytes\xff", &odd)]);
+            let main = pack.blob(&version);
+            let lib = pack.blob(&(i / 2).to_string());
+            let lib = pack.tree(&[("mod.rs", &lib, false)]);
+            let src = pack.tree(&[("lib", &lib, true), ("main.rs", &main, false)]);
+            let readme = pack.blob(&(i / 3).to_string());
+            let tree = pack.tree(&[("README", &readme, false), ("docs", &docs, true), ("src", &src, true)]);
+            let parents: Vec<&Sha> = head.iter().collect();
+            let author = ["alice@example.com", "bob@example.com", "carol@example.com"][i % 3];
+            head = Some(pack.commit_by(&tree, &parents, author, 1000 * (i as i64 + 1)));
+        }
+        let head = head.unwrap();
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let all = || ChangeCounterOptions {
+            include_shas: true,
+            normalized_heat: true,
+            bus_factor: true,
+            top_authors: Some(2),
+            ..Default::default()
+        };
+        let option_sets = [
+            ChangeCounterOptions::default(),
+            all(),
+            ChangeCounterOptions { max_output_nodes: Some(4), ..all() },
+            ChangeCounterOptions { max_output_nodes: Some(1), ..Default::default() },
+            ChangeCounterOptions { directories_only: true, ..all() },
+        ];
+        for options in option_sets {
+            let mut counter = ChangeCounter::new(&repo, &head, options);
+            counter.walk().unwrap();
+            let tree = counter.build_tree().unwrap();
+            let mut json = Vec::new();
+            counter.write_tree_json(&mut json).unwrap();
+            assert_eq!(String::from_utf8(json).unwrap(), serde_json::to_string(&tree).unwrap());
+        }
+
+        // The trees above did have the optional fields
+        let mut counter = ChangeCounter::new(&repo, &head, ChangeCounterOptions { max_output_nodes: Some(4), ..all() });
+        counter.walk().unwrap();
+        let json = serde_json::to_value(counter.build_tree().unwrap()).unwrap();
+        assert_eq!(json["truncated"], true);
+        assert!(json["omittedNodes"].is_u64());
+        assert!(json["normalizedHeat"].is_f64());
+        assert!(json["busFactor"].is_u64());
+        assert!(json["children"][0]["topAuthors"][0]["email"].as_str().is_some());
+        let bytes = &json["children"][0]["children"][0];
+        assert!(bytes["rawName"].as_str().is_some());
+        assert!(bytes["blobSha"].as_str().is_some());
+    }
+
+    #[test]
+    fn ofs_delta() {
+        let mut pack = TestPack::default();
+        let base = pack.blob("fn main() {}\n");
+        let first = pack.delta(&base, b"fn main() { run() }\n", true);
+        // A delta on top of a delta, found by offset too
+        let second = pack.delta(&first, b"fn main() { run(); stop() }\n", true);
+        let by_ref = pack.delta(&base, b"fn other() {}\n", false);
+        let tree = pack.tree(&[("main.rs", &second, false), ("other.rs", &by_ref, false)]);
+        pack.commit(&tree, &[]);
+        let options = ParseOptions { retain_blobs: true, ..Default::default() };
+        let repo = parse_pack_with_options(&pack.finish(), &options).unwrap();
+
+        assert_eq!(repo.warnings, []);
+        assert_eq!(repo.stats.num_deltas, 3);
+        assert_eq!(repo.stats.max_delta_depth, 2);
+        assert_eq!(repo.blobs[&first], b"fn main() { run() }\n");
+        assert_eq!(repo.blobs[&second], b"fn main() { run(); stop() }\n");
+        assert_eq!(repo.blobs[&by_ref], b"fn other() {}\n");
+        assert_eq!(repo.trees[&tree].len(), 2);
+    }
+
+    #[test]
+    fn malformed_deltas() {
+        let base = b"hello world";
+        let invalid = |offset| Err(ParseError::InvalidDelta { offset });
+        // Copies 5 bytes from offset 6
+        assert_eq!(apply_delta(base, &[11, 5, 0x91, 6, 5]), Ok(b"world".to_vec()));
+
+        assert!(apply_delta(base, &[]).is_err());
+        assert!(apply_delta(base, &[0x80]).is_err());
+        // The base is 11 bytes, not 10
+        assert_eq!(apply_delta(base, &[10, 5, 0x91, 6, 5]), invalid(0));
+        assert_eq!(apply_delta(base, &[11, 1, 0]), invalid(2));
+        // Copies from past the end of the base, the second one 0x10000 bytes as the size is left out
+        assert_eq!(apply_delta(base, &[11, 5, 0x91, 8, 5]), invalid(2));
+        assert_eq!(apply_delta(base, &[11, 0, 0x81, 0]), invalid(2));
+        // Cut off in an instruction
+        assert_eq!(apply_delta(base, &[11, 5, 0x91, 6]), invalid(4));
+        assert_eq!(apply_delta(base, &[11, 3, 5, b'a']), invalid(2));
+        // Makes 5 bytes, not the 6 it declares, and a huge declared size isn't allocated up front
+        assert_eq!(apply_delta(base, &[11, 6, 0x91, 6, 5]), invalid(5));
+        assert_eq!(apply_delta(base, &[11, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x91, 6, 5]), invalid(9));
+    }
+
+    #[test]
+    fn pack_framing() {
+        let mut pack = TestPack::default();
+        let tree = pack.tree(&[]);
+        let head = pack.commit(&tree, &[]);
+        let pack = pack.finish();
+
+        for prefix in [&b""[..], b"0008", b"0008NAK\n"] {
+            let framed = [prefix, &pack].concat();
+            assert_eq!(strip_pack_framing(&framed), Ok(&pack[..]));
+            assert!(parse_pack(&framed).unwrap().commit(&head).is_some());
+        }
+        assert_eq!(strip_pack_framing(&[&[b'x'; 16][..], &pack].concat()), Err(ParseError::MissingPackHeader));
+        assert_eq!(strip_pack_framing(b"PAC"), Err(ParseError::MissingPackHeader));
+    }
+
+    #[test]
+    fn merge_commit_counts() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("merged", "0"), ("left", "0"), ("right", "0")]);
+        let root = pack.commit(&tree, &[]);
+        let tree = pack.files(&[("merged", "0"), ("left", "1"), ("right", "0")]);
+        let left = pack.commit(&tree, &[&root]);
+        let tree = pack.files(&[("merged", "0"), ("left", "0"), ("right", "1")]);
+        let right = pack.commit(&tree, &[&root]);
+        // merged differs from both parents, left and right from one each
+        let tree = pack.files(&[("merged", "1"), ("left", "1"), ("right", "1")]);
+        let head = pack.commit(&tree, &[&left, &right]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let counts = |merge_strategy| {
+            let tree = ChangeCounter::process(&repo, &head, ChangeCounterOptions { merge_strategy, ..Default::default() }).unwrap();
+            ["merged", "left", "right"].iter().map(|name| {
+                let node = child(&tree, name).unwrap();
+                (node.num_changes, node.num_commits)
+            }).collect::<Vec<_>>()
+        };
+        assert_eq!(counts(MergeStrategy::AllParents), [(2, 1), (2, 2), (2, 2)]);
+        assert_eq!(counts(MergeStrategy::FirstParent), [(1, 1), (1, 1), (2, 2)]);
+    }
+
+    #[cfg(feature = "myers")]
+    #[test]
+    fn numstat() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("src/f", "a\nb\nc\n"), ("image", "\0\x01")]);
+        let root = pack.commit(&tree, &[]);
+        let tree = pack.files(&[("src/f", "a\nx\ny\nz\nc\n"), ("image", "\0\x02")]);
+        let head = pack.commit(&tree, &[&root]);
+        let options = ParseOptions { retain_blobs: true, ..Default::default() };
+        let repo = parse_pack_with_options(&pack.finish(), &options).unwrap();
+
+        let line = |path: &str, added, removed| NumstatLine { path: path.to_owned(), added, removed };
+        // Adds 3 lines and removes 1, and the binary file is "-" like git shows it
+        assert_eq!(repo.numstat(&head).unwrap(), [line("image", None, None), line("src/f", Some(3), Some(1))]);
+        // A root commit is compared to an empty tree
+        assert_eq!(repo.numstat(&root).unwrap(), [line("image", None, None), line("src/f", Some(3), Some(0))]);
+        assert!(matches!(repo.numstat(&[0; 20]), Err(ProcessError::MissingCommit { .. })));
+    }
+
+    #[test]
+    fn truncated_tree() {
+        let mut pack = TestPack::default();
+        let mut head: Option<Sha> = None;
+        for i in 0..4 {
+            let (hot, warm, tie) = (i.to_string(), (i.min(2)).to_string(), (i.min(1)).to_string());
+            let tree = pack.files(&[("a/hot", &hot), ("b/warm", &warm), ("b/cold", "cold"), ("tie1", &tie), ("tie2", &tie)]);
+            let parents: Vec<&Sha> = head.iter().collect();
+            head = Some(pack.commit(&tree, &parents));
+        }
+        let head = head.unwrap();
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let truncated = |max_output_nodes| {
+            let options = ChangeCounterOptions { max_output_nodes: Some(max_output_nodes), ..Default::default() };
+            let tree = ChangeCounter::process(&repo, &head, options).unwrap();
+            let names = tree.children.iter()
+                .flat_map(|node| std::iter::once(node.name.clone()).chain(node.children.iter().map(move |file| format!("{}/{}", node.name, file.name))))
+                .collect::<Vec<_>>();
+            (tree.truncated, tree.omitted_nodes, names, serde_json::to_string(&tree).unwrap())
+        };
+
+        let (flag, omitted, names, json) = truncated(5);
+        assert!(flag);
+        assert_eq!(omitted, Some(2));
+        // Most changes first, and tie1 before tie2 by path
+        assert_eq!(names, ["a", "a/hot", "b", "b/warm", "tie1"]);
+        assert_eq!(truncated(5).3, json);
+        // Directories are ranked by their own changes, so b still fits but warm doesn't
+        assert_eq!(truncated(3).2, ["a", "a/hot", "b"]);
+        let (flag, omitted, names, _) = truncated(7);
+        assert!(!flag);
+        assert_eq!(omitted, None);
+        assert_eq!(names.len(), 7);
+    }
+
+    #[test]
+    fn changed_directories() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("src/deep/main.rs", "a"), ("src/lib.rs", "lib"), ("docs/readme", "readme")]);
+        let root = pack.commit(&tree, &[]);
+        let tree = pack.files(&[("src/deep/main.rs", "b"), ("src/lib.rs", "lib"), ("docs/readme", "readme")]);
+        let head = pack.commit(&tree, &[&root]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let tree = ChangeCounter::process(&repo, &head, ChangeCounterOptions::default()).unwrap();
+        assert_eq!(tree.changed, Some(true));
+        // Changed through a file two levels down
+        assert_eq!(child(&tree, "src").unwrap().changed, Some(true));
+        assert_eq!(child(&tree, "docs").unwrap().changed, Some(false));
+        // Only set on directories
+        assert_eq!(child(child(&tree, "src").unwrap(), "lib.rs").unwrap().changed, None);
+    }
+
+    #[test]
+    fn malformed_objects() {
+        let parse = |obj_type: PackObjectType, data: &[u8]| {
+            let mut pack = TestPack::default();
+            pack.add(obj_type, data.to_vec());
+            parse_pack(&pack.finish()).err()
+        };
+        assert_eq!(parse(PackObjectType::ObjCommit, b"author A <a@example.com> 1 +0000\n\nno tree"), Some(ParseError::MissingTree));
+        assert_eq!(
+            parse(PackObjectType::ObjCommit, b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nparent xyz\n\nbad parent"),
+            Some(ParseError::InvalidSha { value: "xyz".to_owned() }),
+        );
+        // An entry with a cut off sha, and a name without a mode
+        assert_eq!(parse(PackObjectType::ObjTree, b"100644 f\0short"), Some(ParseError::InvalidTreeEntry { offset: 0 }));
+        assert_eq!(parse(PackObjectType::ObjTree, b"f\0aaaaaaaaaaaaaaaaaaaa"), Some(ParseError::InvalidTreeEntry { offset: 0 }));
+
+        // With several malformed objects it's the first one in the pack that is reported, every time
+        let mut pack = TestPack::default();
+        for i in 0..20 {
+            pack.blob(&i.to_string());
+        }
+        pack.add(PackObjectType::ObjTree, b"100644 f\0short".to_vec());
+        for i in 0..20 {
+            pack.add(PackObjectType::ObjCommit, format!("author A <a@example.com> {} +0000\n\nno tree", i).into_bytes());
+        }
+        let data = pack.finish();
+        for _ in 0..10 {
+            assert_eq!(parse_pack(&data).err(), Some(ParseError::InvalidTreeEntry { offset: 0 }));
+        }
+    }
+
+    #[test]
+    fn stream_parser() {
+        let mut pack = TestPack::default();
+        let main = pack.blob(&"fn main() {}\n".repeat(20));
+        let changed = pack.delta(&main, "fn main() { run() }\n".repeat(20).as_bytes(), true);
+        let again = pack.delta(&changed, "fn main() { stop() }\n".repeat(20).as_bytes(), false);
+        let src = pack.tree(&[("main.rs", &main, false)]);
+        let tree = pack.files(&[("README", "readme")]);
+        let root = pack.commit(&tree, &[]);
+        let tree = pack.tree(&[("src", &src, true)]);
+        let head = pack.commit(&tree, &[&root]);
+        let later = pack.tree(&[("main.rs", &again, false), ("old.rs", &changed, false)]);
+        let tree = pack.tree(&[("src", &later, true)]);
+        pack.commit(&tree, &[&head]);
+        let data = [&b"0008NAK\n"[..], &pack.finish()].concat();
+        let whole = parse_pack(&data).unwrap();
+
+        for chunk_size in [1, 7, 100, data.len()] {
+            let mut parser = PackStreamParser::new(ParseOptions::default(), HashAlgo::Sha1);
+            for chunk in data.chunks(chunk_size) {
+                parser.push(chunk).unwrap();
+            }
+            let streamed = parser.finish().unwrap();
+            assert_eq!(serde_json::to_string(&streamed).unwrap(), serde_json::to_string(&whole).unwrap());
+            assert_eq!(streamed.stats.num_deltas, 2);
+            let mut shas: Vec<&Sha> = streamed.trees.keys().chain(streamed.commits.keys()).collect();
+            let mut expected: Vec<&Sha> = whole.trees.keys().chain(whole.commits.keys()).collect();
+            shas.sort();
+            expected.sort();
+            assert_eq!(shas, expected);
+            assert_eq!(streamed.commit(&head).unwrap().parents(), [root.clone()]);
+        }
+    }
+
+    #[test]
+    fn follow_renames() {
+        let mut pack = TestPack::default();
+        let mut head: Option<Sha> = None;
+        let versions: [&[(&str, &str)]; 6] = [
+            &[("old/name.rs", "0"), ("lib/a", "a0")],
+            &[("old/name.rs", "1"), ("lib/a", "a1")],
+            &[("old/name.rs", "2"), ("lib/a", "a1")],
+            // Moved to another directory, and lib renamed as a whole
+            &[("new/name.rs", "2"), ("util/a", "a1")],
+            &[("new/name.rs", "3"), ("util/a", "a2")],
+            &[("new/name.rs", "3"), ("util/a", "a2"), ("other", "other")],
+        ];
+        for files in versions.iter() {
+            let tree = pack.files(files);
+            let parents: Vec<&Sha> = head.iter().collect();
+            head = Some(pack.commit(&tree, &parents));
+        }
+        let head = head.unwrap();
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let counts = |follow_renames| {
+            let mut counter = ChangeCounter::new(&repo, &head, ChangeCounterOptions { follow_renames, ..Default::default() });
+            counter.walk().unwrap();
+            (counter.changes_for("new/name.rs"), counter.changes_for("util/a"))
+        };
+        // The changes before the move count for where the files ended up, the move itself doesn't count
+        assert_eq!(counts(true), (Some(3), Some(2)));
+        assert_eq!(counts(false), (Some(1), Some(1)));
+    }
+
+    #[test]
+    fn additions_and_deletions() {
+        let mut pack = TestPack::default();
+        let mut head: Option<Sha> = None;
+        let versions: [&[(&str, &str)]; 4] = [
+            &[("gone/x", "x"), ("gone/y", "y"), ("src/kept", "0")],
+            &[("gone/x", "x"), ("gone/y", "y"), ("src/kept", "0"), ("src/added", "0")],
+            // gone is deleted as a whole, and a file of src
+            &[("src/added", "0")],
+            &[("src/added", "1")],
+        ];
+        for files in versions.iter() {
+            let tree = pack.files(files);
+            let parents: Vec<&Sha> = head.iter().collect();
+            head = Some(pack.commit(&tree, &parents));
+        }
+        let head = head.unwrap();
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let counts = |count_additions, count_deletions| {
+            let options = ChangeCounterOptions { count_additions, count_deletions, ..Default::default() };
+            let mut counter = ChangeCounter::new(&repo, &head, options);
+            counter.walk().unwrap();
+            (counter.changes_for("src/added"), counter.changes_for("src"), counter.changes_for(""))
+        };
+        assert_eq!(counts(false, false), (Some(1), Some(1), Some(1)));
+        // Adding the three files of the root commit and src/added counts too
+        assert_eq!(counts(true, false), (Some(2), Some(3), Some(5)));
+        // Deleting src/kept counts for src, and the files of gone for the root
+        assert_eq!(counts(false, true), (Some(1), Some(2), Some(4)));
+        assert_eq!(counts(true, true), (Some(2), Some(4), Some(8)));
+    }
+}
@@ -0,0 +1,39 @@
+/**
+ * Matches a path against a glob pattern. The supported syntax is:
+ * `*` matches any characters except `/`, `**` matches any characters including `/`
+ * (and when a slash follows, also no directory at all), `?` matches a single character except `/`.
+ * Everything else only matches itself.
+ */
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    matches_from(&pattern, &path)
+}
+
+fn matches_from(pattern: &[char], path: &[char]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            if rest.first() == Some(&'/') && matches_from(&rest[1..], path) {
+                return true;
+            }
+            (0..=path.len()).any(|start| matches_from(rest, &path[start..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for start in 0..=path.len() {
+                if matches_from(rest, &path[start..]) {
+                    return true;
+                }
+                if start < path.len() && path[start] == '/' {
+                    // A single star stays within one directory
+                    return false;
+                }
+            }
+            false
+        }
+        Some('?') => !path.is_empty() && path[0] != '/' && matches_from(&pattern[1..], &path[1..]),
+        Some(c) => path.first() == Some(c) && matches_from(&pattern[1..], &path[1..]),
+    }
+}
@@ -0,0 +1,682 @@
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+use crate::git::{CommitInfo, FileChange, GitCommit, GitObject, HashAlgo, ParseError, ParsePackResult, ProcessError, Sha};
+use crate::linediff;
+
+/**
+ * One commit of a changelog and the files it changed compared to its first parent.
+ */
+pub struct ChangelogEntry {
+    pub commit: CommitInfo,
+    pub changes: Vec<FileChange>,
+    pub omitted: usize, // Changed files left out because of max_files
+}
+
+/**
+ * All commits reachable from start, including start itself.
+ * Parents that aren't in the pack (e.g. in a shallow pack) are left out.
+ */
+pub fn reachable(pack: &ParsePackResult, start: &[u8]) -> HashSet<Sha> {
+    let mut seen = HashSet::new();
+    let mut stack = vec![start.to_vec()];
+
+    while let Some(sha) = stack.pop() {
+        if let Some(commit) = pack.commit(&sha) {
+            if seen.insert(sha) {
+                stack.extend(commit.parents().iter().cloned());
+            }
+        }
+    }
+
+    seen
+}
+
+const FROM_A: u8 = 1;
+const FROM_B: u8 = 2;
+const STALE: u8 = 4; // Below a merge base
+
+/**
+ * The state of walk_from_both: the flags of every commit seen so far and the ones still to visit.
+ */
+struct BothWalk<'a> {
+    pack: &'a ParsePackResult,
+    done: u8,
+    flags: HashMap<Sha, u8>,
+    queue: BinaryHeap<(u32, Sha)>,
+    num_undone: usize, // Commits in the queue that don't have all the done flags
+}
+
+impl BothWalk<'_> {
+    fn paint(&mut self, sha: &[u8], new_flags: u8) {
+        let commit = match self.pack.commit(sha) {
+            Some(commit) => commit,
+            None => return,
+        };
+        match self.flags.get_mut(sha) {
+            Some(flags) => {
+                if *flags & self.done != self.done && (*flags | new_flags) & self.done == self.done {
+                    self.num_undone -= 1;
+                }
+                *flags |= new_flags;
+            }
+            None => {
+                self.flags.insert(sha.to_vec(), new_flags);
+                self.queue.push((commit.generation(), sha.to_vec()));
+                if new_flags & self.done != self.done {
+                    self.num_undone += 1;
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Walks the commits reachable from a and b, highest generation first, with flags for which of them
+ * each commit is reachable from. A commit is only visited after all its descendants in the walk, so
+ * its flags are final by then. visit returns more flags to pass on to the parents.
+ * The walk stops once all commits left have all the done flags, without going further down the history.
+ */
+fn walk_from_both(pack: &ParsePackResult, a: &[u8], b: &[u8], done: u8, mut visit: impl FnMut(&Sha, u8) -> u8) {
+    let mut walk = BothWalk {
+        pack,
+        done,
+        flags: HashMap::new(),
+        queue: BinaryHeap::new(),
+        num_undone: 0,
+    };
+    walk.paint(a, FROM_A);
+    walk.paint(b, FROM_B);
+
+    while walk.num_undone > 0 {
+        let (_, sha) = walk.queue.pop().unwrap();
+        let mut flags = walk.flags[&sha];
+        if flags & done != done {
+            walk.num_undone -= 1;
+        }
+        flags |= visit(&sha, flags);
+        for parent in pack.commit(&sha).unwrap().parents() {
+            walk.paint(parent, flags);
+        }
+    }
+}
+
+/**
+ * How many commits are reachable from a but not from b (ahead), and from b but not from a (behind).
+ * For unrelated histories that's simply all commits on either side.
+ */
+pub fn ahead_behind(pack: &ParsePackResult, a: &[u8], b: &[u8]) -> (usize, usize) {
+    let mut ahead = 0;
+    let mut behind = 0;
+
+    walk_from_both(pack, a, b, FROM_A | FROM_B, |_, flags| {
+        match flags {
+            FROM_A => ahead += 1,
+            FROM_B => behind += 1,
+            _ => {}
+        }
+        0
+    });
+
+    (ahead, behind)
+}
+
+/**
+ * The best common ancestors of a and b, ordered by sha: the commits reachable from both that aren't
+ * ancestors of another such commit. Usually that's one commit, criss-cross merges can have several,
+ * and unrelated histories none.
+ */
+pub fn merge_base(pack: &ParsePackResult, a: &[u8], b: &[u8]) -> Vec<Sha> {
+    let mut bases = Vec::new();
+
+    walk_from_both(pack, a, b, STALE, |sha, flags| {
+        // Any common ancestor found later is below this one, as they come in order of generation
+        if flags == FROM_A | FROM_B {
+            bases.push(sha.clone());
+            STALE
+        } else {
+            0
+        }
+    });
+
+    bases.sort();
+    bases
+}
+
+/**
+ * The first-parent history from head, newest first, with the files each commit changed.
+ * With max_files only that many changes are listed per commit, the rest are counted in omitted.
+ * A commit whose parent isn't in the pack is treated like a root commit, and the history ends there.
+ * Fails if the tree of a commit on the way is missing.
+ */
+pub fn changelog(pack: &ParsePackResult, head: &[u8], max_files: Option<usize>) -> Result<Vec<ChangelogEntry>, ProcessError> {
+    let mut entries = Vec::new();
+    let mut next = pack.commit(head).map(|commit| (head.to_vec(), commit));
+
+    while let Some((sha, commit)) = next {
+        let parent = commit.parents().first().and_then(|parent| pack.commit(parent).map(|commit| (parent.clone(), commit)));
+
+        let mut changes = pack.diff_paths(parent.as_ref().map(|(_, parent)| parent.tree_sha()), commit.tree_sha())?;
+        let mut omitted = 0;
+        if let Some(max_files) = max_files {
+            omitted = changes.len().saturating_sub(max_files);
+            changes.truncate(max_files);
+        }
+
+        entries.push(ChangelogEntry {
+            commit: CommitInfo::from((&sha, commit)),
+            changes,
+            omitted,
+        });
+        next = parent;
+    }
+
+    Ok(entries)
+}
+
+/**
+ * The commits reachable from head that changed the file or directory at path (like "src/main.rs"),
+ * newest first. A merge only counts if path differs from all its parents, so changes that came in
+ * from a branch show up once, at the commit on that branch. Commits whose parents aren't in the pack
+ * count like root commits, as having added path if they have it.
+ */
+pub fn file_history(pack: &ParsePackResult, head: &[u8], path: &str) -> Vec<CommitInfo> {
+    let entry_sha = |commit: &GitCommit| pack.find_entry(commit.tree_sha(), path).map(|entry| entry.sha.clone());
+
+    let mut history: Vec<(Sha, &GitCommit)> = reachable(pack, head)
+        .into_iter()
+        .map(|sha| {
+            let commit = pack.commit(&sha).unwrap();
+            (sha, commit)
+        })
+        .filter(|(_, commit)| {
+            let current = entry_sha(commit);
+            let parents: Vec<&GitCommit> = commit.parents().iter().filter_map(|parent| pack.commit(parent)).collect();
+            if parents.is_empty() {
+                return current.is_some();
+            }
+            parents.iter().all(|parent| entry_sha(parent) != current)
+        })
+        .collect();
+    history.sort_by(|(a_sha, a), (b_sha, b)| b.date().cmp(&a.date()).then(a_sha.cmp(b_sha)));
+
+    history.iter().map(|(sha, commit)| CommitInfo::from((sha, *commit))).collect()
+}
+
+/**
+ * For every line of the file at path in head, the line number (from 1) and the commit that last changed it.
+ *
+ * Goes back along first parents, matching lines between each version and the one before. A line
+ * belongs to the oldest commit that still has it. Only exact line matches count, and the file is
+ * followed under its path only, so a file that was renamed starts over at the rename.
+ * Needs the blobs to be retained. Returns None if the file isn't in head or its contents weren't kept.
+ */
+pub fn blame(pack: &ParsePackResult, head: &[u8], path: &str) -> Option<Vec<(usize, Sha)>> {
+    let mut sha = head.to_vec();
+    let mut commit = pack.commit(head)?;
+    let mut blob_sha = pack.find_entry(commit.tree_sha(), path)?.sha.clone();
+    let mut blob = file_contents(pack, &blob_sha)?;
+
+    let num_lines = linediff::count_lines(blob);
+    let mut origins: Vec<Option<Sha>> = vec![None; num_lines];
+    // Lines that aren't attributed yet, as (line in head, line in the current version)
+    let mut pending: Vec<(usize, usize)> = (0..num_lines).map(|line| (line, line)).collect();
+
+    while !pending.is_empty() {
+        let parent = commit.parents().first().and_then(|parent_sha| pack.commit(parent_sha).map(|parent| (parent_sha.clone(), parent)));
+        let previous = parent.as_ref().and_then(|(_, parent)| {
+            let entry = pack.find_entry(parent.tree_sha(), path)?;
+            Some((entry.sha.clone(), file_contents(pack, &entry.sha)?))
+        });
+
+        let (parent_sha, parent) = match (parent, previous) {
+            (Some(parent), Some((previous_sha, previous))) => {
+                if previous_sha != blob_sha {
+                    // Lines that aren't in the previous version were introduced by this commit
+                    let matches = linediff::matching_lines(previous, blob);
+                    pending.retain_mut(|(line, current)| match matches[*current] {
+                        Some(before) => {
+                            *current = before;
+                            true
+                        }
+                        None => {
+                            origins[*line] = Some(sha.clone());
+                            false
+                        }
+                    });
+                    blob_sha = previous_sha;
+                    blob = previous;
+                }
+                parent
+            }
+            _ => {
+                // The file starts here (or the history in the pack does), so the rest is from this commit
+                for (line, _) in pending.drain(..) {
+                    origins[line] = Some(sha.clone());
+                }
+                break;
+            }
+        };
+
+        sha = parent_sha;
+        commit = parent;
+    }
+
+    Some(origins.into_iter().enumerate().map(|(line, origin)| (line + 1, origin.unwrap())).collect())
+}
+
+/**
+ * The commits reachable from head as a GraphViz digraph, with an edge from every commit to each of its parents.
+ * Nodes are labeled with the short sha and the summary. With max_commits only the newest commits are included.
+ */
+pub fn commit_graph_dot(pack: &ParsePackResult, head: &[u8], max_commits: Option<usize>) -> String {
+    let mut commits = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue = BinaryHeap::new();
+    if let Some(commit) = pack.commit(head) {
+        queue.push((commit.date(), head.to_vec()));
+    }
+
+    // Newest first, so a cap keeps the recent history
+    while let Some((_, sha)) = queue.pop() {
+        if commits.len() == max_commits.unwrap_or(usize::MAX) {
+            break;
+        }
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+        let commit = pack.commit(&sha).unwrap();
+        for parent in commit.parents() {
+            if let Some(parent_commit) = pack.commit(parent) {
+                queue.push((parent_commit.date(), parent.clone()));
+            }
+        }
+        commits.push((sha, commit));
+    }
+
+    let mut dot = String::from("digraph commits {\n");
+    for (sha, commit) in &commits {
+        let label = format!("{} {}", &hex::encode(sha)[..7], commit.summary());
+        dot.push_str(&format!("    \"{}\" [label=\"{}\"];\n", hex::encode(sha), label.replace('\\', "\\\\").replace('"', "\\\"")));
+    }
+    for (sha, commit) in &commits {
+        for parent in commit.parents() {
+            if seen.contains(parent) {
+                dot.push_str(&format!("    \"{}\" -> \"{}\";\n", hex::encode(sha), hex::encode(parent)));
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/**
+ * The commits of the pack as a commit-graph file (.git/objects/info/commit-graph), which git reads
+ * instead of the commits themselves to walk the history faster. It's version 1 of the format, with
+ * GitCommit::generation as generation numbers. Every parent of a commit in it has to be in it too, so
+ * commits whose history is cut off in the pack (e.g. in a shallow pack) are left out, along with
+ * everything on top of them.
+ */
+pub fn write_commit_graph(pack: &ParsePackResult) -> Vec<u8> {
+    /*
+     * Format:
+     * "CGPH", version 1, hash version (1 SHA-1, 2 SHA-256), number of chunks, 0 base graphs,
+     * then a table of (4 byte chunk id, 8 byte offset) ending with id 0 at the end of the last chunk.
+     * OIDF: 256 cumulative counts by first sha byte, OIDL: the shas in order,
+     * CDAT: per commit its tree, the positions of two parents and its level and commit date,
+     * EDGE: the parents after the first of merges with more than two, then the hash of it all.
+     */
+    const PARENT_NONE: u32 = 0x7000_0000;
+    const OCTOPUS: u32 = 0x8000_0000; // In CDAT the second parent points into EDGE, in EDGE it marks the last parent
+
+    // Parents before children, so a commit is complete if its parents are
+    let mut commits: Vec<(&Sha, &GitCommit)> = pack.commits().collect();
+    commits.sort_by_key(|(_, commit)| commit.generation());
+    let mut complete = HashSet::new();
+    for (sha, commit) in commits {
+        if commit.parents().iter().all(|parent| complete.contains(parent)) {
+            complete.insert(sha);
+        }
+    }
+    let mut shas: Vec<&Sha> = complete.into_iter().collect();
+    shas.sort();
+    let positions: HashMap<&Sha, u32> = shas.iter().enumerate().map(|(i, sha)| (*sha, i as u32)).collect();
+
+    let mut fanout = Vec::with_capacity(256 * 4);
+    for byte in 0..=255u8 {
+        let count = shas.iter().filter(|sha| sha[0] <= byte).count() as u32;
+        fanout.extend_from_slice(&count.to_be_bytes());
+    }
+    let lookup: Vec<u8> = shas.iter().flat_map(|sha| sha.iter().cloned()).collect();
+
+    let mut data = Vec::new();
+    let mut edges: Vec<u32> = Vec::new();
+    for sha in &shas {
+        let commit = pack.commit(sha).unwrap();
+        let parents: Vec<u32> = commit.parents().iter().map(|parent| positions[parent]).collect();
+        data.extend_from_slice(commit.tree_sha());
+        data.extend_from_slice(&parents.first().cloned().unwrap_or(PARENT_NONE).to_be_bytes());
+        let second = match parents.len() {
+            0 | 1 => PARENT_NONE,
+            2 => parents[1],
+            _ => {
+                let second = OCTOPUS | edges.len() as u32;
+                edges.extend(&parents[1..]);
+                *edges.last_mut().unwrap() |= OCTOPUS;
+                second
+            }
+        };
+        data.extend_from_slice(&second.to_be_bytes());
+        // 30 bits of generation, then 34 bits of commit date
+        let generation = commit.generation().min((1 << 30) - 1) as u64;
+        let date = commit.date().clamp(0, (1 << 34) - 1) as u64;
+        data.extend_from_slice(&((generation << 34) | date).to_be_bytes());
+    }
+    let edges: Vec<u8> = edges.iter().flat_map(|edge| edge.to_be_bytes()).collect();
+
+    let mut chunks: Vec<(&[u8; 4], &[u8])> = vec![(b"OIDF", &fanout), (b"OIDL", &lookup), (b"CDAT", &data)];
+    if !edges.is_empty() {
+        chunks.push((b"EDGE", &edges));
+    }
+
+    let mut graph = Vec::new();
+    graph.extend_from_slice(b"CGPH");
+    graph.push(1);
+    graph.push(match pack.hash_algorithm {
+        HashAlgo::Sha1 => 1,
+        HashAlgo::Sha256 => 2,
+    });
+    graph.push(chunks.len() as u8);
+    graph.push(0);
+
+    let mut offset = (8 + (chunks.len() + 1) * 12) as u64;
+    for (id, chunk) in &chunks {
+        graph.extend_from_slice(*id);
+        graph.extend_from_slice(&offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    graph.extend_from_slice(&[0; 4]);
+    graph.extend_from_slice(&offset.to_be_bytes());
+    for (_, chunk) in &chunks {
+        graph.extend_from_slice(chunk);
+    }
+
+    let checksum = pack.hash_algorithm.digest(&graph);
+    graph.extend(checksum);
+    graph
+}
+
+/**
+ * A commit as a commit-graph file stores it.
+ */
+pub struct CommitGraphEntry {
+    pub sha: Sha,
+    pub tree: Sha,
+    pub parents: Vec<Sha>,
+    pub generation: u32,
+    pub date: i64, // Of the committer
+}
+
+/**
+ * Reads a commit-graph file like write_commit_graph writes, or git does with version 1 and without
+ * a chain of base graphs. Gives the commits ordered by sha.
+ */
+pub fn read_commit_graph(data: &[u8]) -> Result<Vec<CommitGraphEntry>, ParseError> {
+    const PARENT_NONE: u32 = 0x7000_0000;
+    const OCTOPUS: u32 = 0x8000_0000;
+
+    let bytes = |range: std::ops::Range<usize>| data.get(range).ok_or(ParseError::InvalidCommitGraph);
+    let u32_at = |chunk: &[u8], p: usize| -> Result<u32, ParseError> {
+        let bytes = chunk.get(p..p + 4).ok_or(ParseError::InvalidCommitGraph)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+
+    let header = bytes(0..8)?;
+    if &header[..4] != b"CGPH" || header[4] != 1 || header[7] != 0 {
+        return Err(ParseError::InvalidCommitGraph);
+    }
+    let hash_algorithm = match header[5] {
+        1 => HashAlgo::Sha1,
+        2 => HashAlgo::Sha256,
+        _ => return Err(ParseError::InvalidCommitGraph),
+    };
+    let hash_size = hash_algorithm.size();
+    let content_len = data.len().checked_sub(hash_size).ok_or(ParseError::InvalidCommitGraph)?;
+    if hash_algorithm.digest(&data[..content_len]) != data[content_len..] {
+        return Err(ParseError::InvalidCommitGraph);
+    }
+
+    // Each chunk goes up to where the next one in the table starts
+    let num_chunks = header[6] as usize;
+    let mut table = Vec::with_capacity(num_chunks + 1);
+    for i in 0..=num_chunks {
+        let entry = bytes(8 + i * 12..8 + (i + 1) * 12)?;
+        table.push((&entry[..4], u64::from_be_bytes(entry[4..].try_into().unwrap()) as usize));
+    }
+    let chunk = |id: &[u8; 4]| -> Result<Option<&[u8]>, ParseError> {
+        match table.windows(2).find(|window| window[0].0 == id) {
+            Some(window) if window[0].1 <= window[1].1 && window[1].1 <= content_len => Ok(Some(&data[window[0].1..window[1].1])),
+            Some(_) => Err(ParseError::InvalidCommitGraph),
+            None => Ok(None),
+        }
+    };
+    let required = |id: &[u8; 4]| chunk(id)?.ok_or(ParseError::InvalidCommitGraph);
+    let (fanout, lookup, commit_data) = (required(b"OIDF")?, required(b"OIDL")?, required(b"CDAT")?);
+    let edges = chunk(b"EDGE")?.unwrap_or(&[]);
+
+    let count = u32_at(fanout, 255 * 4)? as usize;
+    let entry_size = hash_size + 16;
+    if lookup.len() != count * hash_size || commit_data.len() != count * entry_size {
+        return Err(ParseError::InvalidCommitGraph);
+    }
+    let sha_at = |position: u32| -> Result<Sha, ParseError> {
+        let p = position as usize * hash_size;
+        lookup.get(p..p + hash_size).map(|sha| sha.to_vec()).ok_or(ParseError::InvalidCommitGraph)
+    };
+
+    let mut commits = Vec::with_capacity(count);
+    for (i, entry) in commit_data.chunks(entry_size).enumerate() {
+        let mut parents = Vec::new();
+        let first = u32_at(entry, hash_size)?;
+        if first != PARENT_NONE {
+            parents.push(sha_at(first)?);
+        }
+        let second = u32_at(entry, hash_size + 4)?;
+        if second & OCTOPUS != 0 {
+            // The rest of the parents are in EDGE, up to the one with the high bit set
+            let mut p = (second & !OCTOPUS) as usize * 4;
+            loop {
+                let edge = u32_at(edges, p)?;
+                parents.push(sha_at(edge & !OCTOPUS)?);
+                if edge & OCTOPUS != 0 {
+                    break
+                }
+                p += 4;
+            }
+        } else if second != PARENT_NONE {
+            parents.push(sha_at(second)?);
+        }
+        // 30 bits of generation, then 34 bits of commit date
+        let generation_and_date = u64::from_be_bytes(entry[hash_size + 8..].try_into().unwrap());
+        commits.push(CommitGraphEntry {
+            sha: sha_at(i as u32)?,
+            tree: entry[..hash_size].to_vec(),
+            parents,
+            generation: (generation_and_date >> 34) as u32,
+            date: (generation_and_date & ((1 << 34) - 1)) as i64,
+        });
+    }
+    Ok(commits)
+}
+
+fn file_contents<'a>(pack: &'a ParsePackResult, sha: &[u8]) -> Option<&'a [u8]> {
+    match pack.get_object(sha)? {
+        GitObject::Blob(data) => Some(data),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::{parse_pack, parse_pack_with_options, ParseOptions};
+    use crate::git::tests::TestPack;
+
+    #[test]
+    fn commit_graph_round_trip() {
+        /*
+         * root - a - b ------ merge - octopus
+         *         \          /       /  /
+         *          c ------ d ------'  /
+         *          e -----------------'  (another root)
+         */
+        let mut pack = TestPack::default();
+        let blob = pack.blob("contents");
+        let tree = pack.tree(&[("file", &blob, false)]);
+        let root = pack.commit(&tree, &[]);
+        let a = pack.commit(&tree, &[&root]);
+        let b = pack.commit(&tree, &[&a]);
+        let c = pack.commit(&tree, &[&a]);
+        let d = pack.commit(&tree, &[&c]);
+        let merge = pack.commit(&tree, &[&b, &d]);
+        let e = pack.commit(&tree, &[]);
+        let octopus = pack.commit(&tree, &[&merge, &d, &e]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let graph = read_commit_graph(&write_commit_graph(&repo)).unwrap();
+        assert_eq!(graph.len(), 8);
+        assert!(graph.windows(2).all(|pair| pair[0].sha < pair[1].sha));
+        for entry in &graph {
+            let commit = repo.commit(&entry.sha).unwrap();
+            assert_eq!(entry.tree, commit.tree_sha());
+            assert_eq!(entry.parents, commit.parents());
+            assert_eq!(entry.generation, commit.generation());
+            assert_eq!(entry.date, commit.date());
+        }
+
+        let generation = |sha: &Sha| graph.iter().find(|entry| &entry.sha == sha).unwrap().generation;
+        let expected = [(&root, 1), (&a, 2), (&b, 3), (&c, 3), (&d, 4), (&merge, 5), (&e, 1), (&octopus, 6)];
+        for (sha, level) in expected.iter() {
+            assert_eq!(generation(sha), *level);
+        }
+    }
+
+    #[test]
+    fn corrupt_commit_graph() {
+        let mut pack = TestPack::default();
+        let tree = pack.tree(&[]);
+        let root = pack.commit(&tree, &[]);
+        pack.commit(&tree, &[&root]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+        let graph = write_commit_graph(&repo);
+
+        for p in [0, 20, graph.len() - 30] {
+            let mut corrupt = graph.clone();
+            corrupt[p] ^= 1;
+            assert!(matches!(read_commit_graph(&corrupt), Err(ParseError::InvalidCommitGraph)));
+        }
+        assert!(matches!(read_commit_graph(&graph[..graph.len() - 1]), Err(ParseError::InvalidCommitGraph)));
+        assert!(matches!(read_commit_graph(&[]), Err(ParseError::InvalidCommitGraph)));
+    }
+
+    #[test]
+    fn ahead_behind_diamond() {
+        /*
+         * root - left ---- merge - c1 - c2
+         *    \            /
+         *     `- right --'
+         * other1 - other2  (another root)
+         */
+        let mut pack = TestPack::default();
+        let tree = pack.tree(&[]);
+        let root = pack.commit(&tree, &[]);
+        let left = pack.commit(&tree, &[&root]);
+        let right = pack.commit(&tree, &[&root]);
+        let merge = pack.commit(&tree, &[&left, &right]);
+        let c1 = pack.commit(&tree, &[&merge]);
+        let c2 = pack.commit(&tree, &[&c1]);
+        let other1 = pack.commit(&tree, &[]);
+        let other2 = pack.commit(&tree, &[&other1]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        assert_eq!(ahead_behind(&repo, &c2, &merge), (2, 0));
+        assert_eq!(ahead_behind(&repo, &merge, &c2), (0, 2));
+        assert_eq!(ahead_behind(&repo, &left, &right), (1, 1));
+        assert_eq!(ahead_behind(&repo, &c2, &c2), (0, 0));
+        assert_eq!(ahead_behind(&repo, &c2, &other2), (6, 2));
+        assert_eq!(merge_base(&repo, &c2, &right), [right.clone()]);
+        assert!(merge_base(&repo, &c2, &other2).is_empty());
+    }
+
+    #[test]
+    fn criss_cross_merge_base() {
+        /*
+         * root - a1 - a2 - a3 - x
+         *    \     \ /
+         *     \     X
+         *      \   / \
+         *       b1 -- y
+         * a2 and y both merge a1 and b1, so those are the best common ancestors of x and y, found
+         * although x is further down the history than y
+         */
+        let mut pack = TestPack::default();
+        let tree = pack.tree(&[]);
+        let root = pack.commit(&tree, &[]);
+        let a1 = pack.commit(&tree, &[&root]);
+        let b1 = pack.commit(&tree, &[&root]);
+        let a2 = pack.commit(&tree, &[&a1, &b1]);
+        let a3 = pack.commit(&tree, &[&a2]);
+        let x = pack.commit(&tree, &[&a3]);
+        let y = pack.commit(&tree, &[&b1, &a1]);
+        let repo = parse_pack(&pack.finish()).unwrap();
+
+        let mut expected = vec![a1.clone(), b1.clone()];
+        expected.sort();
+        assert_eq!(merge_base(&repo, &x, &y), expected);
+        assert_eq!(merge_base(&repo, &y, &x), expected);
+        assert_eq!(merge_base(&repo, &a2, &y), expected);
+        assert_eq!(merge_base(&repo, &x, &a1), [a1.clone()]);
+        assert_eq!(ahead_behind(&repo, &x, &y), (3, 1));
+    }
+
+    #[test]
+    fn blame_three_commits() {
+        let mut pack = TestPack::default();
+        let tree = pack.files(&[("src/f", "a\nb\nc\n")]);
+        let first = pack.commit(&tree, &[]);
+        let tree = pack.files(&[("src/f", "a\nB\nc\nd\n")]);
+        let second = pack.commit(&tree, &[&first]);
+        let tree = pack.files(&[("src/f", "x\na\nB\nc\nd\n"), ("other", "other")]);
+        let third = pack.commit(&tree, &[&second]);
+        let tree = pack.files(&[("src/f", "x\na\nB\nc\nd\n"), ("other", "changed")]);
+        let head = pack.commit(&tree, &[&third]);
+        let options = ParseOptions { retain_blobs: true, ..Default::default() };
+        let repo = parse_pack_with_options(&pack.finish(), &options).unwrap();
+
+        let expected = [(1, &third), (2, &first), (3, &second), (4, &first), (5, &second)];
+        assert_eq!(blame(&repo, &head, "src/f").unwrap(), expected.iter().map(|&(line, sha)| (line, sha.clone())).collect::<Vec<_>>());
+        assert_eq!(blame(&repo, &head, "missing"), None);
+        assert_eq!(blame(&parse_pack(&pack.finish()).unwrap(), &head, "src/f"), None);
+    }
+
+    #[test]
+    fn git_commit_graph() {
+        /*
+         * The pack and commit-graph of a small repository with an octopus merge, from
+         * `git repack -ad` and `git -c commitGraph.generationVersion=1 commit-graph write --reachable`
+         */
+        let repo = parse_pack(include_bytes!("testdata/history.pack")).unwrap();
+        let graph = include_bytes!("testdata/history.commit-graph");
+
+        assert_eq!(write_commit_graph(&repo), &graph[..]);
+        let entries = read_commit_graph(graph).unwrap();
+        assert_eq!(entries.len(), 8);
+        let head = entries.iter().find(|entry| entry.sha == hex::decode("5c88503c63ac3a44bd4859569754691684e846d5").unwrap()).unwrap();
+        assert_eq!(head.generation, 5);
+        assert_eq!(head.date, 1600000360);
+        let octopus = entries.iter().find(|entry| entry.sha == head.parents[0]).unwrap();
+        assert_eq!(octopus.parents.len(), 4);
+    }
+}
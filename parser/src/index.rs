@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::git::{HashAlgo, ParseError, Sha};
+
+/**
+ * The objects of a pack index (.idx, version 2) with the offsets they are stored at in the pack, ordered by sha.
+ */
+#[derive(Clone)]
+pub struct PackIndex {
+    pub entries: Vec<(Sha, usize)>,
+}
+
+impl PackIndex {
+    pub fn parse(data: &[u8], hash_algorithm: HashAlgo) -> Result<PackIndex, ParseError> {
+        /*
+         * Index format (version 2):
+         * \377tOc, version, 256 cumulative counts by first sha byte,
+         * then for all n objects the shas, the crc32s and the offsets.
+         * Offsets with the MSB set point into a table of 8 byte offsets for packs over 2GB.
+         */
+        let hash_size = hash_algorithm.size();
+        let read_u32 = |p: usize| -> Result<u32, ParseError> {
+            let bytes = data.get(p..p + 4).ok_or(ParseError::InvalidIndex)?;
+            Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+        };
+
+        if data.get(0..4) != Some(&b"\xfftOc"[..]) || read_u32(4)? != 2 {
+            return Err(ParseError::InvalidIndex);
+        }
+        let count = read_u32(8 + 255 * 4)? as usize;
+        // Each object takes a sha, a crc32 and an offset, and the index ends with the pack's and its own checksum.
+        // Checked before anything is allocated for count, which could be anything in a corrupt file
+        let shas = 8 + 256 * 4;
+        let min_size = count.checked_mul(hash_size + 8)
+            .and_then(|size| size.checked_add(shas + 2 * hash_size))
+            .ok_or(ParseError::InvalidIndex)?;
+        if data.len() < min_size {
+            return Err(ParseError::InvalidIndex);
+        }
+        let offsets = shas + count * (hash_size + 4);
+        let large_offsets = offsets + count * 4;
+
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let sha = data.get(shas + i * hash_size..shas + (i + 1) * hash_size).ok_or(ParseError::InvalidIndex)?;
+            let offset = read_u32(offsets + i * 4)?;
+            let offset = if offset & (1 << 31) != 0 {
+                let p = ((offset & !(1 << 31)) as usize).checked_mul(8).and_then(|p| p.checked_add(large_offsets));
+                let bytes = p.and_then(|p| data.get(p..p.checked_add(8)?)).ok_or(ParseError::InvalidIndex)?;
+                u64::from_be_bytes(bytes.try_into().unwrap()) as usize
+            } else {
+                offset as usize
+            };
+            entries.push((sha.to_vec(), offset));
+        }
+        Ok(PackIndex { entries })
+    }
+
+    /**
+     * The sha of each object by its offset in the pack.
+     */
+    pub fn by_offset(&self) -> HashMap<usize, &Sha> {
+        self.entries.iter().map(|(sha, offset)| (*offset, sha)).collect()
+    }
+}
@@ -1,12 +1,11 @@
-extern crate console_error_panic_hook;
-use wasm_bindgen::prelude::*;
-
-mod git;
-
-#[wasm_bindgen]
-pub fn process_pack(data: &[u8], head_ref: &[u8]) -> JsValue {
-    console_error_panic_hook::set_once();
-    let result = git::parse_pack(data);
-    let root = git::ChangeCounter::process(&result, head_ref);
-    JsValue::from_serde(&root).unwrap()
-}
\ No newline at end of file
+pub mod git;
+mod glob;
+pub mod graph;
+pub mod index;
+mod linediff;
+pub mod refs;
+#[cfg(feature = "schema")]
+pub mod schema;
+// The JS bindings, leave out the wasm feature to use the crate natively without wasm-bindgen
+#[cfg(feature = "wasm")]
+pub mod wasm;
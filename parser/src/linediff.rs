@@ -0,0 +1,203 @@
+#[cfg(not(feature = "myers"))]
+use std::collections::HashMap;
+
+/**
+ * Counts the lines added plus the lines removed between two versions of a file.
+ *
+ * This compares the files as multisets of lines, so moving a line around is not a change.
+ * That's a lot cheaper than a real diff and good enough for churn metrics.
+ */
+#[cfg(not(feature = "myers"))]
+pub fn line_churn(a: &[u8], b: &[u8], ignore_whitespace: bool) -> u32 {
+    let mut counts = HashMap::<Vec<u8>, i64>::new();
+
+    for line in lines(a, ignore_whitespace) {
+        *counts.entry(line).or_insert(0) += 1;
+    }
+    for line in lines(b, ignore_whitespace) {
+        *counts.entry(line).or_insert(0) -= 1;
+    }
+
+    counts.values().map(|count| count.unsigned_abs() as u32).sum()
+}
+
+/**
+ * Counts the lines added plus the lines removed between two versions of a file, using a real diff.
+ */
+#[cfg(feature = "myers")]
+pub fn line_churn(a: &[u8], b: &[u8], ignore_whitespace: bool) -> u32 {
+    let (added, removed) = line_diff(a, b, ignore_whitespace);
+    added + removed
+}
+
+/**
+ * The lines added and the lines removed between two versions of a file, as `git diff --numstat` counts them.
+ */
+#[cfg(feature = "myers")]
+pub fn line_diff(a: &[u8], b: &[u8], ignore_whitespace: bool) -> (u32, u32) {
+    let a = lines(a, ignore_whitespace);
+    let b = lines(b, ignore_whitespace);
+
+    // Every line that isn't in the longest common subsequence was either added or removed
+    let common = (a.len() + b.len() - edit_distance(&a, &b)) / 2;
+    ((b.len() - common) as u32, (a.len() - common) as u32)
+}
+
+/**
+ * The length of the shortest edit script from a to b, with Myers' greedy algorithm.
+ * Takes O((n + m) * d) time, so it's fast when the versions are similar.
+ */
+#[cfg(feature = "myers")]
+fn edit_distance(a: &[Vec<u8>], b: &[Vec<u8>]) -> usize {
+    let max = a.len() + b.len();
+    let mut v = vec![0isize; 2 * max + 2];
+
+    for d in 0..=max {
+        if extend_paths(a, b, &mut v, max, d as isize) {
+            return d;
+        }
+    }
+
+    max
+}
+
+/**
+ * One round of Myers' algorithm. v[offset + k] is how far along a the furthest path on diagonal k
+ * (x - y = k) with d - 1 edits got, this moves them on to d edits. Returns whether one of the paths
+ * reached the end of both a and b.
+ */
+fn extend_paths(a: &[Vec<u8>], b: &[Vec<u8>], v: &mut [isize], offset: usize, d: isize) -> bool {
+    let n = a.len() as isize;
+    let m = b.len() as isize;
+    let index = |k: isize| (offset as isize + k) as usize;
+
+    let mut k = -d;
+    while k <= d {
+        // Either go down from diagonal k + 1 (an insertion) or right from k - 1 (a deletion)
+        let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+            v[index(k + 1)]
+        } else {
+            v[index(k - 1)] + 1
+        };
+        let mut y = x - k;
+
+        // Follow the matching lines for free
+        while x < n && y < m && a[x as usize] == b[y as usize] {
+            x += 1;
+            y += 1;
+        }
+        v[index(k)] = x;
+
+        if x >= n && y >= m {
+            return true;
+        }
+        k += 2;
+    }
+
+    false
+}
+
+pub fn count_lines(data: &[u8]) -> usize {
+    lines(data, false).len()
+}
+
+/**
+ * For every line of b, the line of a it was kept from, or None if it is new.
+ *
+ * Lines are matched along a shortest edit script, found with Myers' algorithm after skipping a common
+ * start and end. Tracing the script back needs the paths of every round, so that takes O(d²) memory
+ * for d edits. Past MAX_MATCH_EDITS edits it gives up, and all lines between the common start and end
+ * count as new.
+ */
+pub fn matching_lines(a: &[u8], b: &[u8]) -> Vec<Option<usize>> {
+    let a = lines(a, false);
+    let b = lines(b, false);
+    let mut matches = vec![None; b.len()];
+
+    // Most edits only touch a small part of a file, so skip what is the same at the start and end
+    let mut prefix = 0;
+    while prefix < a.len() && prefix < b.len() && a[prefix] == b[prefix] {
+        matches[prefix] = Some(prefix);
+        prefix += 1;
+    }
+    let mut suffix = 0;
+    while suffix < a.len() - prefix && suffix < b.len() - prefix && a[a.len() - 1 - suffix] == b[b.len() - 1 - suffix] {
+        matches[b.len() - 1 - suffix] = Some(a.len() - 1 - suffix);
+        suffix += 1;
+    }
+
+    let a = &a[prefix..a.len() - suffix];
+    let b = &b[prefix..b.len() - suffix];
+
+    // trace[d][d + k] is where the path on diagonal k with d edits got to
+    let max = a.len() + b.len();
+    let mut v = vec![0isize; 2 * max + 2];
+    let mut trace = Vec::new();
+    for d in 0..=max.min(MAX_MATCH_EDITS) as isize {
+        let done = extend_paths(a, b, &mut v, max, d);
+        trace.push(v[(max as isize - d) as usize..=(max as isize + d) as usize].to_vec());
+        if !done {
+            continue;
+        }
+
+        // Go back from the end along the edits, the lines followed between them are the matches
+        let (mut x, mut y) = (a.len() as isize, b.len() as isize);
+        for d in (1..trace.len() as isize).rev() {
+            let previous = |k: isize| trace[d as usize - 1][(d - 1 + k) as usize];
+            let k = x - y;
+            let (edit_x, edit_y) = if k == -d || (k != d && previous(k - 1) < previous(k + 1)) {
+                (previous(k + 1), previous(k + 1) - k - 1)
+            } else {
+                (previous(k - 1), previous(k - 1) - k + 1)
+            };
+            // Where the path got to right after the edit, an insertion moves down, a deletion right
+            let (start_x, start_y) = if edit_x - edit_y > k { (edit_x, edit_y + 1) } else { (edit_x + 1, edit_y) };
+            while x > start_x && y > start_y {
+                x -= 1;
+                y -= 1;
+                matches[prefix + y as usize] = Some(prefix + x as usize);
+            }
+            x = edit_x;
+            y = edit_y;
+        }
+        while x > 0 && y > 0 {
+            x -= 1;
+            y -= 1;
+            matches[prefix + y as usize] = Some(prefix + x as usize);
+        }
+        break;
+    }
+
+    matches
+}
+
+/**
+ * How many edits matching_lines goes through before it gives up, which keeps the trace to some 32 MB.
+ */
+const MAX_MATCH_EDITS: usize = 2000;
+
+fn lines(data: &[u8], ignore_whitespace: bool) -> Vec<Vec<u8>> {
+    let mut lines = Vec::new();
+    if data.is_empty() {
+        return lines;
+    }
+
+    for line in data.split(|&byte| byte == b'\n') {
+        if ignore_whitespace {
+            // Drop all whitespace, so reindented lines compare equal and blank lines disappear
+            let normalized: Vec<u8> = line.iter().cloned().filter(|byte| !byte.is_ascii_whitespace()).collect();
+            if !normalized.is_empty() {
+                lines.push(normalized);
+            }
+        } else {
+            lines.push(line.to_vec());
+        }
+    }
+
+    // A trailing newline terminates the last line, it does not start a new one
+    if !ignore_whitespace && data.last() == Some(&b'\n') {
+        lines.pop();
+    }
+
+    lines
+}
@@ -1,14 +1,92 @@
 use std::env;
 use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
-mod git;
+use rsgit::git;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+
+    if args.len() == 4 && args[1] == "cat" {
+        cat(&args[2], &args[3]);
+        return;
+    }
+    #[cfg(feature = "schema")]
+    if args.len() == 2 && args[1] == "schema" {
+        println!("{}", rsgit::schema::output_schema());
+        return;
+    }
+
     let file = &args[1];
     println!("Opening {}", file);
 
     let buf = fs::read(file).unwrap();
+    let options = git::ParseOptions {
+        loose_objects: loose_objects_of(file),
+        ..Default::default()
+    };
+
+    if let Err(error) = git::parse_pack_with_options(&buf, &options) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+/**
+ * The objects directory of the repository a pack is in (".git/objects" for ".git/objects/pack/pack-1234.pack"),
+ * so a thin pack can have its bases there as loose objects. None for a pack somewhere else.
+ */
+fn loose_objects_of(file: &str) -> Option<PathBuf> {
+    let pack_dir = Path::new(file).parent()?;
+    if pack_dir.file_name()? != "pack" {
+        return None;
+    }
+    pack_dir.parent().map(Path::to_path_buf)
+}
+
+/**
+ * Prints an object from the pack like `git cat-file -p <sha>` would.
+ */
+fn cat(file: &str, sha: &str) {
+    let buf = fs::read(file).unwrap();
+    let options = git::ParseOptions {
+        retain_blobs: true,
+        loose_objects: loose_objects_of(file),
+        ..Default::default()
+    };
+    let result = match git::parse_pack_with_options(&buf, &options) {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+
+    let sha = match hex::decode(sha) {
+        Ok(sha) => sha,
+        Err(_) => {
+            eprintln!("Not a valid sha: {}", sha);
+            process::exit(1);
+        }
+    };
 
-    git::parse_pack(&buf[8..]);
+    match result.get_object(&sha) {
+        Some(git::GitObject::Commit(commit)) => {
+            io::stdout().write_all(commit.raw()).unwrap();
+        }
+        Some(git::GitObject::Tree(tree)) => {
+            for entry in tree {
+                println!("{:06o} {} {}\t{}", entry.mode, entry.object_type(), hex::encode(&entry.sha), entry.name);
+            }
+        }
+        Some(git::GitObject::Blob(blob)) => {
+            io::stdout().write_all(blob).unwrap();
+        }
+        None => {
+            eprintln!("Object not found in pack");
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,178 @@
+use std::collections::BTreeMap;
+
+use crate::git::Sha;
+
+/**
+ * The refs of a repository by full name ("HEAD", "refs/heads/main", "refs/tags/v1").
+ */
+#[derive(Default)]
+pub struct Refs {
+    pub refs: BTreeMap<String, Sha>,
+    pub head_symref: Option<String>, // The ref HEAD points to, e.g. "refs/heads/main"
+}
+
+impl Refs {
+    pub fn new() -> Refs {
+        Refs::default()
+    }
+
+    /**
+     * Reads the refs of a smart protocol ref advertisement (the response to info/refs?service=git-upload-pack).
+     * The symref=HEAD:... capability on the first ref line tells which branch HEAD points to.
+     * None if the advertisement isn't made of valid pkt-lines.
+     */
+    pub fn parse_advertisement(data: &[u8]) -> Option<Refs> {
+        let mut refs = Refs::new();
+        let mut p = 0;
+
+        while p < data.len() {
+            let length = usize::from_str_radix(std::str::from_utf8(data.get(p..p + 4)?).ok()?, 16).ok()?;
+            if length < 4 {
+                // A flush-pkt (0000) or another special packet without data
+                p += 4;
+                continue;
+            }
+            let line = data.get(p + 4..p + length)?;
+            p += length;
+
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            if line.is_empty() || line.starts_with(b"#") {
+                // A packet without a payload (0004), or the "# service=git-upload-pack" announcement
+                continue;
+            }
+            let (line, capabilities) = match line.iter().position(|&byte| byte == 0) {
+                Some(nul) => (&line[..nul], &line[nul + 1..]),
+                None => (line, &[][..]),
+            };
+            let line = std::str::from_utf8(line).ok()?;
+            let space = line.find(' ')?;
+            let sha = hex::decode(&line[..space]).ok()?;
+            let name = &line[space + 1..];
+
+            for capability in std::str::from_utf8(capabilities).ok()?.split(' ') {
+                if let Some(target) = capability.strip_prefix("symref=HEAD:") {
+                    refs.head_symref = Some(target.to_owned());
+                }
+            }
+            if name == "capabilities^{}" {
+                // Stands in for the first ref when there are none
+                continue;
+            }
+            refs.insert(name, sha);
+        }
+        Some(refs)
+    }
+
+    pub fn insert(&mut self, name: &str, sha: Sha) {
+        self.refs.insert(name.to_owned(), sha);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Sha> {
+        self.refs.get(name)
+    }
+
+    /**
+     * The commit to use when no head was asked for: the branch HEAD points to if it's known, otherwise HEAD
+     * if it's there, then main, then master, then the only branch if there is just one.
+     */
+    pub fn default_head(&self) -> Option<Sha> {
+        if let Some(sha) = self.head_symref.as_ref().and_then(|name| self.refs.get(name)) {
+            return Some(sha.clone());
+        }
+        for name in &["HEAD", "refs/heads/main", "refs/heads/master"] {
+            if let Some(sha) = self.refs.get(*name) {
+                return Some(sha.clone());
+            }
+        }
+
+        let mut branches = self.refs.iter().filter(|(name, _)| name.starts_with("refs/heads/"));
+        match (branches.next(), branches.next()) {
+            (Some((_, sha)), None) => Some(sha.clone()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkt_lines(lines: &[&str]) -> Vec<u8> {
+        lines.iter().map(|line| if line.is_empty() { "0000".to_owned() } else { format!("{:04x}{}", line.len() + 4, line) }).collect::<String>().into_bytes()
+    }
+
+    #[test]
+    fn default_head_prefers_main() {
+        let mut refs = Refs::new();
+        refs.insert("refs/heads/feature", vec![1; 20]);
+        refs.insert("refs/heads/main", vec![2; 20]);
+        assert_eq!(refs.default_head(), Some(vec![2; 20]));
+
+        refs.insert("refs/heads/master", vec![3; 20]);
+        assert_eq!(refs.default_head(), Some(vec![2; 20]));
+        refs.insert("HEAD", vec![4; 20]);
+        assert_eq!(refs.default_head(), Some(vec![4; 20]));
+
+        let mut refs = Refs::new();
+        refs.insert("refs/heads/feature", vec![1; 20]);
+        assert_eq!(refs.default_head(), Some(vec![1; 20]));
+        refs.insert("refs/heads/other", vec![2; 20]);
+        assert_eq!(refs.default_head(), None);
+    }
+
+    #[test]
+    fn advertisement() {
+        let main = "1111111111111111111111111111111111111111";
+        let tag = "2222222222222222222222222222222222222222";
+        let tagged = "3333333333333333333333333333333333333333";
+        let data = pkt_lines(&[
+            "# service=git-upload-pack\n",
+            "",
+            &format!("{} HEAD\0multi_ack symref=HEAD:refs/heads/main agent=git/2.40\n", main),
+            "\n",
+            &format!("{} refs/heads/main\n", main),
+            &format!("{} refs/tags/v1\n", tag),
+            &format!("{} refs/tags/v1^{{}}\n", tagged),
+            "",
+        ]);
+        // A packet without a payload, which git doesn't send but the format allows
+        let data = [&b"0004"[..], &data].concat();
+
+        let refs = Refs::parse_advertisement(&data).unwrap();
+        assert_eq!(refs.refs.keys().collect::<Vec<_>>(), ["HEAD", "refs/heads/main", "refs/tags/v1", "refs/tags/v1^{}"]);
+        assert_eq!(refs.head_symref.as_deref(), Some("refs/heads/main"));
+        // The peeled ref is the commit the annotated tag points to
+        assert_eq!(refs.get("refs/tags/v1^{}"), Some(&hex::decode(tagged).unwrap()));
+        assert_eq!(refs.get("refs/tags/v1"), Some(&hex::decode(tag).unwrap()));
+
+        assert!(Refs::parse_advertisement(b"0009broken").is_none());
+        assert!(Refs::parse_advertisement(b"zzzz").is_none());
+    }
+
+    #[test]
+    fn empty_repository() {
+        // Without refs, a capabilities^{} line with the null sha carries the capabilities
+        let data = pkt_lines(&["0000000000000000000000000000000000000000 capabilities^{}\0multi_ack agent=git/2.40\n", ""]);
+        let refs = Refs::parse_advertisement(&data).unwrap();
+        assert!(refs.refs.is_empty());
+        assert_eq!(refs.head_symref, None);
+        assert_eq!(refs.default_head(), None);
+    }
+
+    #[test]
+    fn symref_head() {
+        let main = "1111111111111111111111111111111111111111";
+        let develop = "2222222222222222222222222222222222222222";
+        let data = pkt_lines(&[
+            &format!("{} HEAD\0multi_ack symref=HEAD:refs/heads/develop agent=git/2.40\n", develop),
+            &format!("{} refs/heads/develop\n", develop),
+            &format!("{} refs/heads/main\n", main),
+            "",
+        ]);
+        let mut refs = Refs::parse_advertisement(&data).unwrap();
+        assert_eq!(refs.head_symref.as_deref(), Some("refs/heads/develop"));
+        // The symref wins over main, even without a HEAD line
+        refs.refs.remove("HEAD");
+        assert_eq!(refs.default_head(), Some(hex::decode(develop).unwrap()));
+    }
+}
@@ -0,0 +1,10 @@
+use crate::git::ExplorerResult;
+
+/**
+ * The JSON Schema of ExplorerResult, what ChangeCounter::analyze returns. TreeNode (what process_pack returns)
+ * and the other output types are in its definitions, with their fields as they are serialized, so the TS types
+ * can be generated from it.
+ */
+pub fn output_schema() -> String {
+    serde_json::to_string_pretty(&schemars::schema_for!(ExplorerResult)).unwrap()
+}
@@ -0,0 +1,342 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use wasm_bindgen::prelude::*;
+
+use crate::git;
+use crate::graph;
+use crate::refs::Refs;
+
+/**
+ * Errors are thrown as plain strings on the JS side.
+ */
+fn to_js_error(error: impl std::fmt::Display) -> JsValue {
+    JsValue::from_str(&error.to_string())
+}
+
+/**
+ * Options can be left out on the JS side to use the defaults.
+ */
+fn counter_options(options: JsValue) -> Result<git::ChangeCounterOptions, JsValue> {
+    if options.is_undefined() || options.is_null() {
+        Ok(git::ChangeCounterOptions::default())
+    } else {
+        options.into_serde().map_err(to_js_error)
+    }
+}
+
+/**
+ * Like counter_options, with an abort flag for the handles that can be aborted from JS.
+ */
+fn abortable_options(options: JsValue) -> Result<(git::ChangeCounterOptions, Arc<AtomicBool>), JsValue> {
+    let mut options = counter_options(options)?;
+    let abort = Arc::new(AtomicBool::new(false));
+    options.abort = Some(abort.clone());
+    Ok((options, abort))
+}
+
+/**
+ * Keeps the blobs the options need, and stops on the same abort flag.
+ */
+fn parse_options_for(options: &git::ChangeCounterOptions) -> git::ParseOptions {
+    git::ParseOptions {
+        retain_blobs: options.needs_blobs(),
+        abort: options.abort.clone(),
+        ..Default::default()
+    }
+}
+
+fn parse_for(data: &[u8], options: &git::ChangeCounterOptions) -> Result<git::ParsePackResult, JsValue> {
+    git::parse_pack_with_options(data, &parse_options_for(options)).map_err(to_js_error)
+}
+
+fn process(data: &[u8], head_ref: &[u8], options: JsValue) -> Result<git::TreeNode, JsValue> {
+    let options = counter_options(options)?;
+    let result = parse_for(data, &options)?;
+    git::ChangeCounter::process(&result, head_ref, options).map_err(to_js_error)
+}
+
+#[wasm_bindgen]
+pub fn process_pack(data: &[u8], head_ref: &[u8], options: JsValue) -> Result<JsValue, JsValue> {
+    console_error_panic_hook::set_once();
+    let root = process(data, head_ref, options)?;
+    Ok(JsValue::from_serde(&root).unwrap())
+}
+
+/**
+ * A parsed pack to run several queries on without parsing it again. The options it's created with
+ * apply to every change tree. The objects stay in wasm memory until free() (which wasm-bindgen adds)
+ * is called on it.
+ *
+ * A long walk can be spread over several calls, so the page stays responsive and can abort it:
+ * start_walk, then walk_steps until it returns true (e.g. awaiting a setTimeout in between), then walk_tree.
+ */
+#[wasm_bindgen]
+pub struct Repository {
+    pack: git::ParsePackResult,
+    options: git::ChangeCounterOptions,
+    abort: Arc<AtomicBool>,
+    walk: Option<(git::Sha, git::WalkState)>, // The head and the state of the walk started with start_walk
+}
+
+#[wasm_bindgen]
+impl Repository {
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: &[u8], options: JsValue) -> Result<Repository, JsValue> {
+        console_error_panic_hook::set_once();
+        let (options, abort) = abortable_options(options)?;
+        let pack = parse_for(data, &options)?;
+        Ok(Repository { pack, options, abort, walk: None })
+    }
+
+    /**
+     * The tree of head_ref, like process_pack returns it.
+     */
+    pub fn change_tree(&self, head_ref: &[u8]) -> Result<JsValue, JsValue> {
+        self.abort.store(false, Ordering::Relaxed);
+        let root = git::ChangeCounter::process(&self.pack, head_ref, self.options.clone()).map_err(to_js_error)?;
+        Ok(JsValue::from_serde(&root).unwrap())
+    }
+
+    /**
+     * Starts a walk of the history of head_ref, replacing the one before.
+     */
+    pub fn start_walk(&mut self, head_ref: &[u8]) {
+        self.abort.store(false, Ordering::Relaxed);
+        let counter = git::ChangeCounter::new(&self.pack, head_ref, self.options.clone());
+        self.walk = Some((head_ref.to_vec(), counter.save_state()));
+    }
+
+    /**
+     * Walks at most max_steps more commits of the walk. Returns whether it's done, or throws
+     * "Processing was aborted" (and drops the walk) if abort was called since the last step.
+     */
+    pub fn walk_steps(&mut self, max_steps: usize) -> Result<bool, JsValue> {
+        let (head, state) = self.walk.take().ok_or_else(|| JsValue::from_str("No walk was started"))?;
+        let mut counter = git::ChangeCounter::load_state(&self.pack, &head, self.options.clone(), state);
+        let done = counter.walk_steps(Some(max_steps)).map_err(to_js_error)?;
+        self.walk = Some((head, counter.save_state()));
+        Ok(done)
+    }
+
+    /**
+     * The tree of the walk, counting the commits it got to so far.
+     */
+    pub fn walk_tree(&self) -> Result<JsValue, JsValue> {
+        let (head, state) = self.walk.as_ref().ok_or_else(|| JsValue::from_str("No walk was started"))?;
+        let counter = git::ChangeCounter::load_state(&self.pack, head, self.options.clone(), state.clone());
+        let root = counter.build_tree().map_err(to_js_error)?;
+        Ok(JsValue::from_serde(&root).unwrap())
+    }
+
+    /**
+     * Makes the next walk_steps throw. It can only take effect between calls, as wasm runs on the JS thread.
+     */
+    pub fn abort(&self) {
+        self.abort.store(true, Ordering::Relaxed);
+    }
+
+    pub fn commit_count(&self) -> usize {
+        self.pack.commits().count()
+    }
+
+    /**
+     * All commits in the pack, like list_commits.
+     */
+    pub fn commits(&self) -> JsValue {
+        JsValue::from_serde(&self.pack.commit_infos()).unwrap()
+    }
+
+    /**
+     * The commits reachable from head_ref that changed path, newest first, see graph::file_history.
+     */
+    pub fn file_history(&self, head_ref: &[u8], path: &str) -> JsValue {
+        JsValue::from_serde(&graph::file_history(&self.pack, head_ref, path)).unwrap()
+    }
+
+    /**
+     * The metadata of the pack, like pack_info.
+     */
+    pub fn info(&self) -> JsValue {
+        JsValue::from_serde(&self.pack).unwrap()
+    }
+}
+
+/**
+ * Like process_pack, but the pack is handed over chunk by chunk as it downloads, e.g. each value of
+ * `response.body.getReader()`, with finish called once it's complete. Only for SHA-1 repositories.
+ */
+#[wasm_bindgen]
+pub struct PackStreamParser {
+    parser: git::PackStreamParser,
+    options: git::ChangeCounterOptions,
+    abort: Arc<AtomicBool>,
+}
+
+#[wasm_bindgen]
+impl PackStreamParser {
+    #[wasm_bindgen(constructor)]
+    pub fn new(options: JsValue) -> Result<PackStreamParser, JsValue> {
+        console_error_panic_hook::set_once();
+        let (options, abort) = abortable_options(options)?;
+        Ok(PackStreamParser {
+            parser: git::PackStreamParser::new(parse_options_for(&options), git::HashAlgo::Sha1),
+            options,
+            abort,
+        })
+    }
+
+    /**
+     * Makes the next push or finish throw "Parsing was aborted", e.g. once the user navigates away.
+     */
+    pub fn abort(&self) {
+        self.abort.store(true, Ordering::Relaxed);
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), JsValue> {
+        self.parser.push(chunk).map_err(to_js_error)
+    }
+
+    /**
+     * The tree of head_ref, like process_pack returns it.
+     */
+    pub fn finish(self, head_ref: &[u8]) -> Result<JsValue, JsValue> {
+        let result = self.parser.finish().map_err(to_js_error)?;
+        let root = git::ChangeCounter::process(&result, head_ref, self.options).map_err(to_js_error)?;
+        Ok(JsValue::from_serde(&root).unwrap())
+    }
+}
+
+/**
+ * Like process_pack, but with the heads and options in one AnalyzeRequest, e.g.
+ * `{ heads: [{ kind: "name", value: "main" }], options: {...} }`. Ref names are looked up in
+ * the ref advertisement, which can be left empty when only shas are used.
+ */
+#[wasm_bindgen]
+pub fn process_request(data: &[u8], advertisement: &[u8], request: JsValue) -> Result<JsValue, JsValue> {
+    console_error_panic_hook::set_once();
+    let request: git::AnalyzeRequest = request.into_serde().map_err(to_js_error)?;
+    let refs = Refs::parse_advertisement(advertisement).ok_or_else(|| JsValue::from_str("Invalid ref advertisement"))?;
+    let result = parse_for(data, &request.options)?;
+    let root = git::ChangeCounter::run(&result, &refs, request).map_err(to_js_error)?;
+    Ok(JsValue::from_serde(&root).unwrap())
+}
+
+/**
+ * Same as process_pack, but the tree comes back as MessagePack bytes (a Uint8Array).
+ * Structs are encoded as maps with the same camelCase keys as the JSON output, so any
+ * MessagePack decoder (e.g. `decode` from @msgpack/msgpack) gives back the same object.
+ */
+#[cfg(feature = "msgpack")]
+#[wasm_bindgen]
+pub fn process_pack_msgpack(data: &[u8], head_ref: &[u8], options: JsValue) -> Result<Vec<u8>, JsValue> {
+    console_error_panic_hook::set_once();
+    let root = process(data, head_ref, options)?;
+    Ok(to_msgpack(&root))
+}
+
+#[cfg(feature = "msgpack")]
+fn to_msgpack(root: &git::TreeNode) -> Vec<u8> {
+    rmp_serde::to_vec_named(root).unwrap()
+}
+
+/**
+ * The tree, pack stats, contributors, the top_k most changed files and the commits reachable from head,
+ * all in one object so the pack only has to be parsed once.
+ */
+#[wasm_bindgen]
+pub fn analyze(data: &[u8], head_ref: &[u8], options: JsValue, top_k: usize) -> Result<JsValue, JsValue> {
+    console_error_panic_hook::set_once();
+    let options = counter_options(options)?;
+    let result = parse_for(data, &options)?;
+    let analysis = git::ChangeCounter::analyze(&result, head_ref, options, top_k).map_err(to_js_error)?;
+    Ok(JsValue::from_serde(&analysis).unwrap())
+}
+
+#[wasm_bindgen]
+pub fn list_commits(data: &[u8]) -> Result<JsValue, JsValue> {
+    console_error_panic_hook::set_once();
+    let result = git::parse_pack(data).map_err(to_js_error)?;
+    Ok(JsValue::from_serde(&result.commit_infos()).unwrap())
+}
+
+#[wasm_bindgen]
+pub fn pack_info(data: &[u8]) -> Result<JsValue, JsValue> {
+    console_error_panic_hook::set_once();
+    let result = git::parse_pack(data).map_err(to_js_error)?;
+    Ok(JsValue::from_serde(&result).unwrap())
+}
+
+/**
+ * Just the k most changed files as `[{ path, changes }]`, for when the whole tree isn't needed.
+ */
+#[wasm_bindgen]
+pub fn top_files(data: &[u8], head_ref: &[u8], k: usize) -> Result<JsValue, JsValue> {
+    console_error_panic_hook::set_once();
+    let result = git::parse_pack(data).map_err(to_js_error)?;
+    let mut counter = git::ChangeCounter::new(&result, head_ref, git::ChangeCounterOptions::default());
+    counter.walk().map_err(to_js_error)?;
+    Ok(JsValue::from_serde(&counter.top_files(k)).unwrap())
+}
+
+/**
+ * The k most changed directories as `[{ path, changes }]`.
+ */
+#[wasm_bindgen]
+pub fn top_directories(data: &[u8], head_ref: &[u8], k: usize) -> Result<JsValue, JsValue> {
+    console_error_panic_hook::set_once();
+    let result = git::parse_pack(data).map_err(to_js_error)?;
+    let mut counter = git::ChangeCounter::new(&result, head_ref, git::ChangeCounterOptions::default());
+    counter.walk().map_err(to_js_error)?;
+    Ok(JsValue::from_serde(&counter.top_directories(k)).unwrap())
+}
+
+/**
+ * Per-path change deltas between two trees returned by process_pack, as `[[path, delta]]`.
+ */
+#[wasm_bindgen]
+pub fn compare_results(old: JsValue, new: JsValue) -> Result<JsValue, JsValue> {
+    console_error_panic_hook::set_once();
+    let old: git::TreeNode = old.into_serde().map_err(to_js_error)?;
+    let new: git::TreeNode = new.into_serde().map_err(to_js_error)?;
+    Ok(JsValue::from_serde(&git::compare_results(&old, &new)).unwrap())
+}
+
+#[cfg(all(test, feature = "msgpack"))]
+mod tests {
+    use super::*;
+    use crate::git::tests::TestPack;
+
+    #[test]
+    fn msgpack_matches_json() {
+        let mut pack = TestPack::default();
+        let mut parent: Option<git::Sha> = None;
+        for i in 0..3 {
+            let changed = pack.blob(&format!("version {}", i));
+            let same = pack.blob("same");
+            let src = pack.tree(&[("main.rs", &changed, false), ("lib.rs", &same, false)]);
+            let docs = pack.tree(&[("readme", &same, false)]);
+            let root = pack.tree(&[("src", &src, true), ("docs", &docs, true), ("Cargo.toml", &changed, false)]);
+            let parents: Vec<&git::Sha> = parent.iter().collect();
+            parent = Some(pack.commit(&root, &parents));
+        }
+        let repo = git::parse_pack(&pack.finish()).unwrap();
+
+        // Options that fill in the optional fields, so they are compared too
+        let mut options = git::ChangeCounterOptions::default();
+        options.include_shas = true;
+        options.normalized_heat = true;
+        options.bus_factor = true;
+        options.top_authors = Some(2);
+        options.max_output_nodes = Some(4);
+        let root = git::ChangeCounter::process(&repo, &parent.unwrap(), options).unwrap();
+
+        let json = serde_json::to_value(&root).unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&to_msgpack(&root)).unwrap();
+        assert_eq!(decoded, json);
+        assert_eq!(json["truncated"], true);
+        assert!(json["omittedNodes"].is_u64());
+        assert!(json["normalizedHeat"].is_f64());
+        assert!(json["topAuthors"].is_array());
+        assert!(json["children"][0]["numChanges"].is_u64());
+    }
+}
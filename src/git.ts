@@ -1,4 +1,4 @@
-import init, { process_pack } from "./pkg/rsgit";
+import init, { PackStreamParser } from "./pkg/rsgit";
 
 export interface AnalyzeSuccess {
 	success: true;
@@ -15,9 +15,31 @@ export type AnalyzeResult = AnalyzeSuccess | AnalyzeFailure;
 
 export interface TreeNode {
 	name: string;
+	/** Hex of the raw name bytes, only present if the name isn't valid UTF-8 */
+	rawName?: string;
 	type: "file" | "directory";
+	/** Hex sha of the file's blob, only present with the includeShas option */
+	blobSha?: string;
 	numChanges: number;
+	/** How many commits changed the file or something below the directory, a merge counting once */
+	numCommits: number;
+	/** Only present (and true) for directories with entries */
+	hasChildren?: boolean;
+	/** How few authors made more than half of a directory's changes, only with the busFactor option */
+	busFactor?: number;
+	/** The authors with the most changes and how many they made, most first, only with the topAuthors option */
+	topAuthors?: { email: string; changes: number }[];
 	children: TreeNode[];
+	/** 1 for a file, the number of files below a directory */
+	numFiles: number;
+	/** For directories, whether any of those files changed */
+	changed?: boolean;
+	/** numChanges per file of a directory, only with the normalizedHeat option */
+	normalizedHeat?: number;
+	/** Only present (and true) on the root if the maxOutputNodes option left nodes out */
+	truncated?: boolean;
+	/** How many nodes were left out, only present on the root of a truncated tree */
+	omittedNodes?: number;
 }
 
 export const followPath = (tree: TreeNode, path: string): TreeNode | null => {
@@ -40,19 +62,25 @@ export const followPath = (tree: TreeNode, path: string): TreeNode | null => {
 
 const wasmInit = init("rsgit_bg.wasm");
 
-export const analyzeRepo = async (repoUrl: string): Promise<AnalyzeResult> => {
+export const analyzeRepo = async (
+	repoUrl: string,
+	signal?: AbortSignal
+): Promise<AnalyzeResult> => {
 	try {
-		return fastAnalyzeRepo(repoUrl);
+		// Awaited so a rejection is caught here too
+		return await fastAnalyzeRepo(repoUrl, signal);
 	} catch (error) {
 		return {
 			success: false,
-			errorMessage: error.message
+			// The parser throws its errors as plain strings
+			errorMessage: error instanceof Error ? error.message : String(error)
 		};
 	}
 };
 
 export const fastAnalyzeRepo = async (
-	repoUrl: string
+	repoUrl: string,
+	signal?: AbortSignal
 ): Promise<AnalyzeResult> => {
 	await wasmInit;
 
@@ -68,29 +96,44 @@ export const fastAnalyzeRepo = async (
 			["Accept", "application/x-git-upload-pack-result"],
 			["Content-Type", "application/x-git-upload-pack-request"]
 		],
-		body: `0057want ${headRef} filter=blob:none agent=repo-explorer\n00000009done\n`
+		body: `0057want ${headRef} filter=blob:none agent=repo-explorer\n00000009done\n`,
+		signal
 	});
 
-	if (!res.ok) {
+	if (!res.ok || !res.body) {
 		throw new Error("Request failed.");
 	}
 
-	const arrayBuffer = await res.arrayBuffer();
-	const data = new Uint8Array(arrayBuffer);
-
-	for (const d of parsePktLines(data)) {
-		if (d.type === "pack") {
-			const headRefBuf = new Uint8Array(
-				headRef.match(/[\da-f]{2}/gi)!.map(h => parseInt(h, 16))
-			);
-			return {
-				success: true,
-				headRef,
-				root: process_pack(d.data, headRefBuf)
-			};
+	// The pack is parsed while it downloads, the parser skips the NAK pkt-line in front of it
+	const parser = new PackStreamParser({});
+	// Aborting also stops the download, but the parser may still have a chunk to get through
+	const abort = () => parser.abort();
+	signal?.addEventListener("abort", abort);
+	try {
+		const reader = res.body.getReader();
+		for (;;) {
+			const { done, value } = await reader.read();
+			if (done) {
+				break;
+			}
+			parser.push(value);
 		}
+	} catch (error) {
+		parser.free();
+		throw error;
+	} finally {
+		// finish runs in one go, so there is nothing left to abort, and the parser is gone after it
+		signal?.removeEventListener("abort", abort);
 	}
-	throw new Error("Could not find pack.");
+
+	const headRefBuf = new Uint8Array(
+		headRef.match(/[\da-f]{2}/gi)!.map(h => parseInt(h, 16))
+	);
+	return {
+		success: true,
+		headRef,
+		root: parser.finish(headRefBuf)
+	};
 };
 
 const dicoverHeadRef = async (baseUrl: string) => {